@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock, Weak};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_util::ready;
+use hyper::body::{Body, Frame, SizeHint};
+use tokio::time::{Instant, Sleep};
+
+use crate::config::{self, BandwidthLimit, Config};
+
+/// Number of entries a table may hold before dropped entries are pruned.
+const MIN_PRUNE_LEN: usize = 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Direction {
+    /// From the client to the destination.
+    Upload,
+    /// From the destination to the client.
+    Download,
+}
+
+/// Token buckets limiting the bandwidth of all traffic, of each listening address, of each
+/// client address, of each egress address and of each client connection.
+#[derive(Clone, Debug)]
+pub struct Bandwidth {
+    limits: Arc<Limits>,
+    global: Arc<Buckets>,
+    listeners: Arc<Mutex<Table<SocketAddr>>>,
+    clients: Arc<Mutex<Table>>,
+    egress: Arc<Mutex<Table>>,
+    /// The buckets of the address the connection was accepted on.
+    listener: Option<Arc<Buckets>>,
+    connection: Arc<Buckets>,
+}
+
+/// The configured rates, copied out of the config whenever it was reloaded so relaying does
+/// not take the config lock.
+#[derive(Debug)]
+struct Limits {
+    /// The config generation the rates were copied from.
+    generation: AtomicU64,
+    global: Rates,
+    per_listener: Rates,
+    per_client: Rates,
+    per_egress: Rates,
+    per_connection: Rates,
+}
+
+impl Limits {
+    fn refresh(&self, config: &RwLock<Config>) {
+        let generation = config::generation();
+        if self.generation.load(Ordering::Acquire) == generation {
+            return;
+        }
+
+        let config = config.read().unwrap();
+        let limits = &config.bandwidth;
+        self.global.store(&limits.global);
+        self.per_listener.store(&limits.per_listener);
+        self.per_client.store(&limits.per_client);
+        self.per_egress.store(&limits.per_egress);
+        self.per_connection.store(&limits.per_connection);
+        self.generation.store(generation, Ordering::Release);
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            // never a real generation, so the rates are read on first use
+            generation: AtomicU64::new(u64::MAX),
+            global: Rates::default(),
+            per_listener: Rates::default(),
+            per_client: Rates::default(),
+            per_egress: Rates::default(),
+            per_connection: Rates::default(),
+        }
+    }
+}
+
+/// Bytes per second in each direction, zero when unlimited.
+#[derive(Debug, Default)]
+struct Rates {
+    upload: AtomicU64,
+    download: AtomicU64,
+}
+
+impl Rates {
+    fn store(&self, limit: &BandwidthLimit) {
+        self.upload
+            .store(limit.upload.unwrap_or(0), Ordering::Relaxed);
+        self.download
+            .store(limit.download.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    fn get(&self, direction: Direction) -> u64 {
+        match direction {
+            Direction::Upload => self.upload.load(Ordering::Relaxed),
+            Direction::Download => self.download.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Table<K = IpAddr> {
+    buckets: HashMap<K, Weak<Buckets>>,
+    prune_len: usize,
+}
+
+impl<K> Default for Table<K> {
+    fn default() -> Self {
+        Self {
+            buckets: HashMap::new(),
+            prune_len: 0,
+        }
+    }
+}
+
+impl<K: Eq + Hash> Table<K> {
+    fn get(&mut self, addr: K) -> Arc<Buckets> {
+        if self.buckets.len() >= self.prune_len.max(MIN_PRUNE_LEN) {
+            self.buckets.retain(|_, buckets| buckets.strong_count() > 0);
+            self.prune_len = self.buckets.len() * 2;
+        }
+
+        if let Some(buckets) = self.buckets.get(&addr).and_then(Weak::upgrade) {
+            return buckets;
+        }
+
+        let buckets = Arc::new(Buckets::default());
+        self.buckets.insert(addr, Arc::downgrade(&buckets));
+        buckets
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    upload: Mutex<Bucket>,
+    download: Mutex<Bucket>,
+}
+
+impl Buckets {
+    fn take(&self, direction: Direction, n: usize, rates: &Rates) -> Duration {
+        let bucket = match direction {
+            Direction::Upload => &self.upload,
+            Direction::Download => &self.download,
+        };
+
+        match rates.get(direction) {
+            0 => Duration::ZERO,
+            rate => bucket.lock().unwrap().take(n, rate),
+        }
+    }
+}
+
+/// A token bucket holding up to one second worth of bytes, it starts out full.
+///
+/// Bytes are sent before they are paid for, the bucket goes into debt and the sender waits
+/// until it is paid off.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Default for Bucket {
+    fn default() -> Self {
+        Self {
+            tokens: f64::INFINITY,
+            last: Instant::now(),
+        }
+    }
+}
+
+impl Bucket {
+    fn take(&mut self, n: usize, rate: u64) -> Duration {
+        let now = Instant::now();
+        let rate = rate as f64;
+
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.last = now;
+
+        self.tokens -= n as f64;
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64(-self.tokens / rate)
+    }
+}
+
+impl Bandwidth {
+    pub fn new() -> Self {
+        Self {
+            limits: Arc::new(Limits::default()),
+            global: Arc::default(),
+            listeners: Arc::default(),
+            clients: Arc::default(),
+            egress: Arc::default(),
+            listener: None,
+            connection: Arc::default(),
+        }
+    }
+
+    /// Returns the limits for a new client connection accepted on `listener`, sharing all
+    /// buckets but the one of the connection.
+    pub fn for_connection(&self, listener: Option<SocketAddr>) -> Self {
+        Self {
+            listener: listener.map(|addr| self.listeners.lock().unwrap().get(addr)),
+            connection: Arc::default(),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the throttle for traffic of `client` sent from the egress address `egress`.
+    ///
+    /// The limits are read from `config` again after it was reloaded, so they follow config
+    /// reloads.
+    pub fn throttle(
+        &self,
+        config: Arc<RwLock<Config>>,
+        client: Option<IpAddr>,
+        egress: Option<IpAddr>,
+    ) -> Throttle {
+        let throttle = Throttle {
+            config,
+            limits: self.limits.clone(),
+            global: self.global.clone(),
+            listener: self.listener.clone(),
+            client: client.map(|addr| self.clients.lock().unwrap().get(addr)),
+            egress: Arc::default(),
+            egress_table: self.egress.clone(),
+            connection: self.connection.clone(),
+        };
+        if let Some(addr) = egress {
+            throttle.set_egress(addr);
+        }
+
+        throttle
+    }
+}
+
+/// The buckets a connection pays its bytes into.
+#[derive(Clone, Debug)]
+pub struct Throttle {
+    config: Arc<RwLock<Config>>,
+    limits: Arc<Limits>,
+    global: Arc<Buckets>,
+    listener: Option<Arc<Buckets>>,
+    client: Option<Arc<Buckets>>,
+    /// Shared by the clones, so the egress address can be set once the connection is made.
+    egress: Arc<OnceLock<Arc<Buckets>>>,
+    egress_table: Arc<Mutex<Table>>,
+    connection: Arc<Buckets>,
+}
+
+impl Throttle {
+    /// Charges the egress address `addr` from now on, unless one is already set.
+    pub fn set_egress(&self, addr: IpAddr) {
+        self.egress
+            .get_or_init(|| self.egress_table.lock().unwrap().get(addr));
+    }
+
+    /// Pays for `n` bytes sent in `direction` and returns how long to pause before sending
+    /// more.
+    pub fn take(&self, direction: Direction, n: usize) -> Duration {
+        self.limits.refresh(&self.config);
+        let limits = &*self.limits;
+
+        let mut delay = self.global.take(direction, n, &limits.global);
+        if let Some(listener) = &self.listener {
+            delay = delay.max(listener.take(direction, n, &limits.per_listener));
+        }
+        if let Some(client) = &self.client {
+            delay = delay.max(client.take(direction, n, &limits.per_client));
+        }
+        if let Some(egress) = self.egress.get() {
+            delay = delay.max(egress.take(direction, n, &limits.per_egress));
+        }
+        delay = delay.max(self.connection.take(direction, n, &limits.per_connection));
+
+        delay
+    }
+
+    /// Pays for `n` bytes sent in `direction` and waits until more may be sent.
+    pub async fn consume(&self, direction: Direction, n: usize) {
+        let delay = self.take(direction, n);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// A body whose data frames are paid for with a [`Throttle`].
+pub struct Throttled<B> {
+    inner: B,
+    throttle: Throttle,
+    direction: Direction,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<B> Throttled<B> {
+    pub fn new(inner: B, throttle: Throttle, direction: Direction) -> Self {
+        Self {
+            inner,
+            throttle,
+            direction,
+            delay: None,
+        }
+    }
+}
+
+impl<B> Body for Throttled<B>
+where
+    B: Body<Data = Bytes> + Unpin,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+
+        if let Some(delay) = &mut this.delay {
+            ready!(delay.as_mut().poll(cx));
+            this.delay = None;
+        }
+
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        if let Some(data) = frame
+            .as_ref()
+            .and_then(|frame| frame.as_ref().ok())
+            .and_then(Frame::data_ref)
+        {
+            let delay = this.throttle.take(this.direction, data.len());
+            if !delay.is_zero() {
+                this.delay = Some(Box::pin(tokio::time::sleep(delay)));
+            }
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u64 = 1000;
+
+    fn assert_close(actual: Duration, expected: Duration) {
+        let diff = actual.abs_diff(expected);
+        assert!(
+            diff < Duration::from_millis(50),
+            "{:?} is not close to {:?}",
+            actual,
+            expected
+        );
+    }
+
+    fn bucket(tokens: f64, elapsed: Duration) -> Bucket {
+        Bucket {
+            tokens,
+            last: Instant::now() - elapsed,
+        }
+    }
+
+    #[test]
+    fn starts_full() {
+        let mut bucket = Bucket::default();
+        assert_eq!(bucket.take(RATE as usize, RATE), Duration::ZERO);
+        assert_close(
+            bucket.take(RATE as usize / 2, RATE),
+            Duration::from_millis(500),
+        );
+    }
+
+    #[test]
+    fn debt_is_paid_off_at_rate() {
+        let mut bucket = Bucket::default();
+        assert_close(bucket.take(3 * RATE as usize, RATE), Duration::from_secs(2));
+        assert_close(bucket.take(RATE as usize, RATE), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let mut bucket = bucket(-(RATE as f64), Duration::from_secs(2));
+        assert_eq!(bucket.take(RATE as usize / 2, RATE), Duration::ZERO);
+        assert_close(bucket.take(RATE as usize, RATE), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn holds_at_most_one_second() {
+        let mut bucket = bucket(0.0, Duration::from_secs(10));
+        assert_eq!(bucket.take(RATE as usize, RATE), Duration::ZERO);
+        assert_close(bucket.take(RATE as usize, RATE), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn unlimited_rate_never_waits() {
+        let buckets = Buckets {
+            upload: Mutex::default(),
+            download: Mutex::default(),
+        };
+        let rates = Rates::default();
+        rates.upload.store(RATE, Ordering::Relaxed);
+
+        for _ in 0..10 {
+            assert_eq!(
+                buckets.take(Direction::Download, 10 * RATE as usize, &rates),
+                Duration::ZERO
+            );
+        }
+        assert_eq!(
+            buckets.take(Direction::Upload, RATE as usize, &rates),
+            Duration::ZERO
+        );
+        assert_close(
+            buckets.take(Direction::Upload, RATE as usize, &rates),
+            Duration::from_secs(1),
+        );
+    }
+
+    #[test]
+    fn connections_share_listener_buckets() {
+        let bandwidth = Bandwidth::new();
+        let a = "127.0.0.1:3000".parse().unwrap();
+        let b = "127.0.0.2:3000".parse().unwrap();
+
+        let first = bandwidth.for_connection(Some(a));
+        let second = bandwidth.for_connection(Some(a));
+        let other = bandwidth.for_connection(Some(b));
+
+        let listener = |bandwidth: &Bandwidth| bandwidth.listener.clone().unwrap();
+        assert!(Arc::ptr_eq(&listener(&first), &listener(&second)));
+        assert!(!Arc::ptr_eq(&listener(&first), &listener(&other)));
+        assert!(!Arc::ptr_eq(&first.connection, &second.connection));
+    }
+}
@@ -1,16 +1,31 @@
+use std::collections::HashMap;
 use std::future::{Future, IntoFuture};
 use std::io;
 use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{mpsc, Arc, RwLock};
 use std::time::Duration;
 
 use config::{ConfigError, File};
 use glob::glob;
-use ipnet::IpNet;
+use ipnet::{IpNet, Ipv6Net};
 use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
 use serde::Deserialize;
 
+use crate::connect::dns::IpPreference;
+use crate::connect::tcp::{ConnectStrategy, ConnectTimeoutMode, RetryPolicy, TcpKeepalive};
+
+/// Bumped every time the config is reloaded.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Returns how often the config was reloaded, so state derived from it can tell when to
+/// refresh without comparing whole sections.
+pub fn generation() -> u64 {
+    GENERATION.load(Ordering::Acquire)
+}
+
 #[derive(Clone, Debug, Deserialize)]
 #[serde(default)]
 pub struct Config {
@@ -28,14 +43,119 @@ pub struct Config {
 
     /// Concurrent connections
     ///
-    /// Specifies the limit of concurrent connections that the server can handle simultaneously.
+    /// Specifies the limit of concurrent client connections that the server can handle
+    /// simultaneously. It is enforced as a hard cap, connections beyond it are queued or
+    /// rejected according to `overload`, so deployments that relied on the default of 1024
+    /// being only the listen backlog may need to raise it. Also used as the listen backlog,
+    /// which needs a restart to change.
     pub concurrent: u32,
 
+    /// Concurrent upstream connections
+    ///
+    /// Limits the simultaneously open tunnels and in-flight plain HTTP requests, unlimited
+    /// when unset.
+    pub concurrent_outbound: Option<u32>,
+
+    /// What to do with connections beyond `concurrent` or `concurrent_outbound`.
+    pub overload: OverloadConfig,
+
     pub connect_timeout: Option<Duration>,
 
+    /// Tuning of upstream TCP connections
+    pub connector: ConnectorConfig,
+
+    /// Retries of failed upstream connections and idempotent plain HTTP requests
+    ///
+    /// e.g. `retry = { attempts = 3, on = ["refused", "reset", "timed_out", "unreachable"] }`.
+    /// Requests are only retried when they failed before any response was received.
+    pub retry: RetryPolicy,
+
+    /// Circuit breaker of destinations
+    ///
+    /// When set, a destination `host:port` that failed `failures` connects in a row is answered
+    /// with 503 for `cooldown`, before a single request is let through to probe it again.
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+
+    /// Tuning of accepted client connections
+    pub inbound: InboundConfig,
+
+    /// Pooling of upstream connections for plain HTTP requests
+    pub pool: PoolConfig,
+
+    /// Timeouts of CONNECT tunnels
+    pub tunnel: TunnelConfig,
+
+    /// Bandwidth limits
+    ///
+    /// Upload and download rates in bytes per second, applied to tunnels and plain HTTP
+    /// bodies. e.g. `per_client = { upload = 1048576, download = 4194304 }`.
+    pub bandwidth: BandwidthConfig,
+
     pub cidr: Option<IpNet>,
 
+    /// Subnet prefix length
+    ///
+    /// When set, each client session is placed in a random subnet of `cidr` with this prefix
+    /// length, and only the remaining host bits are randomized per connection. e.g. with
+    /// `cidr = "2001:db8::/48"` and `cidr_subnet_len = 64`, all connections of a session share
+    /// one random /64.
+    pub cidr_subnet_len: Option<u8>,
+
+    /// Egress address leases
+    ///
+    /// When set, an address of `cidr` is leased exclusively to one client session for as long
+    /// as the session has active upstream connections.
+    pub lease: Option<LeaseConfig>,
+
+    /// Source port range
+    ///
+    /// Restricts the local ports of upstream connections, e.g.
+    /// `source_port_range = { start = 40000, end = 59999 }`.
+    ///
+    /// On Linux 6.3 and later the kernel picks a port of the range at connect time, which it
+    /// narrows down to `net.ipv4.ip_local_port_range`. A range that does not lie within that
+    /// system range is therefore bound explicitly instead, with a warning, as on other systems.
+    /// An explicitly bound port is reserved for its local address alone, so such ranges run out
+    /// of ports sooner.
+    pub source_port_range: Option<RangeInclusive<u16>>,
+
+    /// Egress rate limit
+    ///
+    /// When set, each address of `cidr` is used for at most `requests` requests to the same
+    /// destination domain per `interval`. Once an address has spent its budget, another address
+    /// of `cidr` is chosen instead. Without `lease`, requests on pooled connections are paid
+    /// for once they were sent, so concurrent requests may go slightly over the budget.
+    pub rate_limit: Option<RateLimitConfig>,
+
+    /// Retry on block responses
+    ///
+    /// When set, a destination answering with one of `statuses` or resetting the connection is
+    /// considered to block the egress address. The address is avoided for that domain during
+    /// `cooldown`, and idempotent plain HTTP requests are retried from another address of
+    /// `cidr`.
+    pub block_retry: Option<BlockRetryConfig>,
+
+    /// DNS resolution of upstream hosts
+    pub dns: DnsConfig,
+
+    /// Address family preference
+    ///
+    /// One of `ipv4_only`, `ipv6_only`, `prefer_ipv4`, `prefer_ipv6` or `system`. The `*_only`
+    /// policies drop resolved addresses of the other family, the `prefer_*` ones try the given
+    /// family first and fall back to the other one after the happy eyeballs delay.
+    pub ip_preference: IpPreference,
+
+    /// Built-in DNS server
+    ///
+    /// When set, A and AAAA queries received over UDP and TCP on `bind` are answered with the
+    /// resolver stack of `dns`, including its overrides and cache.
+    pub dns_server: Option<DnsServerConfig>,
+
     pub fallback: Option<IpAddr>,
+
+    /// The [`generation`] this config was loaded in.
+    #[serde(skip)]
+    pub generation: u64,
 }
 
 impl Default for Config {
@@ -44,13 +164,535 @@ impl Default for Config {
             debug: false,
             bind: "0.0.0.0:3000".parse().unwrap(),
             concurrent: 1024,
+            concurrent_outbound: None,
+            overload: OverloadConfig::default(),
             connect_timeout: Some(Duration::from_secs(10)),
+            connector: ConnectorConfig::default(),
+            retry: RetryPolicy::default(),
+            circuit_breaker: None,
+            inbound: InboundConfig::default(),
+            pool: PoolConfig::default(),
+            tunnel: TunnelConfig::default(),
+            bandwidth: BandwidthConfig::default(),
             cidr: None,
+            cidr_subnet_len: None,
+            lease: None,
+            source_port_range: None,
+            rate_limit: None,
+            block_retry: None,
+            dns: DnsConfig::default(),
+            ip_preference: IpPreference::default(),
+            dns_server: None,
             fallback: None,
+            generation: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ConnectorConfig {
+    /// How long to wait for the preferred address family before also trying the other one.
+    ///
+    /// Addresses are tried strictly in order when unset.
+    pub happy_eyeballs_timeout: Option<Duration>,
+
+    /// Set `TCP_NODELAY` on upstream connections.
+    pub nodelay: bool,
+
+    /// Keepalive probes on upstream connections, disabled when unset.
+    pub keepalive: Option<TcpKeepalive>,
+
+    /// `TCP_USER_TIMEOUT` of upstream connections, only supported on Linux, Android and Fuchsia.
+    ///
+    /// How long sent data may stay unacknowledged before the connection is dropped.
+    pub user_timeout: Option<Duration>,
+
+    /// Set `TCP_FASTOPEN_CONNECT` on upstream connections, only supported on Linux.
+    ///
+    /// Data is sent along with the SYN to destinations that handed out a TFO cookie before.
+    /// The connect then completes before the handshake, so `connect_timeout` no longer covers
+    /// it, and refused or unreachable destinations only fail on the first read or write. The
+    /// next address of a host is not tried then, and `retry` and `circuit_breaker` cannot see
+    /// the failure, so TFO is not used while either of them is configured, with a warning when
+    /// the config is loaded.
+    pub fast_open: bool,
+
+    /// `SO_SNDBUF` of upstream connections, the system default when unset.
+    pub send_buffer_size: Option<u32>,
+
+    /// `SO_RCVBUF` of upstream connections, the system default when unset.
+    pub recv_buffer_size: Option<u32>,
+
+    /// How `connect_timeout` applies to hosts with several addresses.
+    ///
+    /// `split` divides it evenly between the addresses, `per_attempt` gives each address the
+    /// full timeout and `total` bounds the whole connect with it. Under `total`, sequential
+    /// attempts each get an equal share of the time left.
+    pub connect_timeout_mode: ConnectTimeoutMode,
+
+    /// How the addresses of a host are tried, `sequential` or `staggered`.
+    ///
+    /// `staggered` alternates between the address families and starts the next attempt after
+    /// `stagger_delay` or as soon as the previous one failed, as described in RFC 8305.
+    pub connect_strategy: ConnectStrategy,
+
+    /// Delay between staggered connection attempts.
+    pub stagger_delay: Duration,
+}
+
+impl Default for ConnectorConfig {
+    fn default() -> Self {
+        Self {
+            happy_eyeballs_timeout: Some(Duration::from_millis(300)),
+            nodelay: false,
+            keepalive: None,
+            user_timeout: None,
+            fast_open: false,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            connect_timeout_mode: ConnectTimeoutMode::Split,
+            connect_strategy: ConnectStrategy::Sequential,
+            stagger_delay: Duration::from_millis(250),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct InboundConfig {
+    /// Keepalive probes on client connections, disabled when unset.
+    pub keepalive: Option<TcpKeepalive>,
+
+    /// `TCP_USER_TIMEOUT` of client connections, only supported on Linux, Android and Fuchsia.
+    pub user_timeout: Option<Duration>,
+
+    /// `TCP_FASTOPEN` queue length of the listening socket, disabled when unset. Only
+    /// supported on Linux, changes need a restart.
+    pub fast_open: Option<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct PoolConfig {
+    /// How long an idle connection is kept open, forever when unset.
+    pub idle_timeout: Option<Duration>,
+
+    /// Most idle connections kept per destination, for each leased egress address or, without
+    /// `lease`, for each pool of `cidr` addresses.
+    pub max_idle_per_host: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Some(Duration::from_secs(90)),
+            max_idle_per_host: 32,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct TunnelConfig {
+    /// How long a tunnel may relay no bytes in either direction, forever when unset.
+    pub idle_timeout: Option<Duration>,
+
+    /// How long a tunnel may stay open at all, forever when unset.
+    pub max_lifetime: Option<Duration>,
+
+    /// How long a tunnel stays open after one side closed its half, forever when unset.
+    pub half_close_timeout: Option<Duration>,
+
+    /// Relay tunnels with `splice(2)` on Linux, so their bytes are not copied through
+    /// userspace.
+    pub splice: bool,
+}
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: None,
+            max_lifetime: None,
+            half_close_timeout: None,
+            splice: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct BandwidthConfig {
+    /// Limits shared by all traffic.
+    pub global: BandwidthLimit,
+
+    /// Limits of each local address clients connect to. When `bind` is an unspecified address,
+    /// each address of the host is limited separately.
+    pub per_listener: BandwidthLimit,
+
+    /// Limits of each client IP address, shared by all its connections.
+    pub per_client: BandwidthLimit,
+
+    /// Limits of each egress address of `cidr`.
+    pub per_egress: BandwidthLimit,
+
+    /// Limits of each client connection, including the tunnel it carries.
+    pub per_connection: BandwidthLimit,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct BandwidthLimit {
+    /// Bytes per second from clients to destinations, unlimited when unset.
+    pub upload: Option<u64>,
+
+    /// Bytes per second from destinations to clients, unlimited when unset.
+    pub download: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failed connects after which the circuit opens.
+    pub failures: u32,
+
+    /// How long an open circuit fails requests before probing the destination.
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failures: 5,
+            cooldown: Duration::from_secs(30),
         }
     }
 }
 
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct OverloadConfig {
+    /// Whether to queue or reject connections beyond the limit.
+    pub policy: OverloadPolicy,
+
+    /// How long the `wait` policy queues a connection before rejecting it.
+    ///
+    /// Waits indefinitely when unset.
+    pub wait_timeout: Option<Duration>,
+}
+
+impl Default for OverloadConfig {
+    fn default() -> Self {
+        Self {
+            policy: OverloadPolicy::Wait,
+            wait_timeout: Some(Duration::from_secs(10)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverloadPolicy {
+    /// Wait for another connection to finish.
+    Wait,
+    /// Answer with 503 right away.
+    Reject,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct LeaseConfig {
+    /// What to do when every address of the pool is leased.
+    pub policy: LeasePolicy,
+
+    /// How long the `wait` policy waits for an address to be released.
+    ///
+    /// Waits indefinitely when unset.
+    pub wait_timeout: Option<Duration>,
+}
+
+impl Default for LeaseConfig {
+    fn default() -> Self {
+        Self {
+            policy: LeasePolicy::Wait,
+            wait_timeout: Some(Duration::from_secs(10)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeasePolicy {
+    /// Wait for another session to release its address.
+    Wait,
+    /// Hand out an address that is already leased.
+    Reuse,
+    /// Fail the request.
+    Reject,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Requests allowed per address and domain in each interval.
+    pub requests: u32,
+
+    /// Length of the interval.
+    pub interval: Duration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            requests: 60,
+            interval: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct BlockRetryConfig {
+    /// Maximum number of attempts per request, including the first one.
+    pub attempts: u32,
+
+    /// Response statuses that mean the egress address is blocked.
+    pub statuses: Vec<u16>,
+
+    /// How long a blocked address is avoided for the domain.
+    pub cooldown: Duration,
+}
+
+impl Default for BlockRetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            statuses: vec![403, 429],
+            cooldown: Duration::from_secs(600),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct DnsConfig {
+    /// Which resolver looks up upstream hosts.
+    pub resolver: ResolverKind,
+
+    /// Nameservers queried by the `stub` resolver, e.g. `["1.1.1.1:53", "[2606:4700::1111]:53"]`.
+    ///
+    /// Read from `/etc/resolv.conf` when empty.
+    pub nameservers: Vec<SocketAddr>,
+
+    /// Domains appended to names with fewer than `ndots` dots.
+    ///
+    /// Read from `/etc/resolv.conf` when both this and `nameservers` are empty.
+    pub search: Vec<String>,
+
+    /// Names with at least this many dots are looked up as is before trying the search list.
+    pub ndots: usize,
+
+    /// How long to wait for a nameserver to answer.
+    pub timeout: Duration,
+
+    /// How many times each nameserver is tried.
+    pub attempts: u32,
+
+    /// Caching of resolved addresses
+    ///
+    /// When set, answers are cached for their TTL, clamped to `min_ttl..=max_ttl`.
+    pub cache: Option<DnsCacheConfig>,
+
+    /// DNS-over-HTTPS servers queried by the `doh` resolver
+    pub doh: DohConfig,
+
+    /// DNS-over-TLS servers queried by the `dot` resolver
+    pub dot: DotConfig,
+
+    /// Hosts pinned to fixed addresses, e.g. `{ "api.example.com" = ["192.0.2.10"] }`.
+    ///
+    /// Pinned hosts are never looked up.
+    pub hosts: HashMap<String, Vec<IpAddr>>,
+
+    /// Nameservers for names under specific suffixes, e.g.
+    /// `[{ suffix = "corp.internal", nameservers = ["10.0.0.53:53"] }]`.
+    ///
+    /// The longest matching suffix wins, other names go to `resolver`.
+    pub rules: Vec<DnsRuleConfig>,
+
+    /// DNS64 for IPv6-only egress
+    ///
+    /// When set, IPv4-only destinations, including IPv4 literal CONNECT targets, are reached
+    /// through addresses synthesized within the NAT64 `prefix`.
+    pub dns64: Option<Dns64Config>,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            resolver: ResolverKind::System,
+            nameservers: Vec::new(),
+            search: Vec::new(),
+            ndots: 1,
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+            cache: None,
+            doh: DohConfig::default(),
+            dot: DotConfig::default(),
+            hosts: HashMap::new(),
+            rules: Vec::new(),
+            dns64: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct DohConfig {
+    /// Endpoints, tried in order, e.g.
+    /// `[{ url = "https://cloudflare-dns.com/dns-query", addrs = ["1.1.1.1"] }]`.
+    pub endpoints: Vec<DohEndpointConfig>,
+
+    /// Dial the endpoints from a random address of `cidr` instead of the default one.
+    pub egress: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct DohEndpointConfig {
+    pub url: String,
+
+    /// Addresses of the endpoint host, e.g. `["1.1.1.1", "2606:4700:4700::1111"]`.
+    ///
+    /// When empty, the host is looked up with the system resolver.
+    #[serde(default)]
+    pub addrs: Vec<IpAddr>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct DotConfig {
+    /// Servers, tried in order, e.g.
+    /// `[{ addr = "1.1.1.1:853", name = "cloudflare-dns.com" }]`.
+    pub servers: Vec<DotServerConfig>,
+
+    /// Dial the servers from a random address of `cidr` instead of the default one.
+    pub egress: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct DotServerConfig {
+    pub addr: SocketAddr,
+
+    /// The name the server certificate is verified against.
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct DnsRuleConfig {
+    /// The domain the rule applies to, including its subdomains.
+    pub suffix: String,
+
+    /// Nameservers queried by the stub resolver for matching names.
+    pub nameservers: Vec<SocketAddr>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Dns64Config {
+    /// The NAT64 prefix, with a length of 32, 40, 48, 56, 64 or 96 bits.
+    pub prefix: Ipv6Net,
+}
+
+impl Default for Dns64Config {
+    fn default() -> Self {
+        Self {
+            prefix: "64:ff9b::/96".parse().unwrap(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct DnsCacheConfig {
+    /// Maximum number of cached names, the least recently used are evicted first.
+    pub capacity: usize,
+
+    /// Lower bound for the TTL of cached addresses, also used when the resolver reports none.
+    pub min_ttl: Duration,
+
+    /// Upper bound for the TTL of cached addresses.
+    pub max_ttl: Duration,
+
+    /// How long a name that does not exist is cached.
+    pub negative_ttl: Duration,
+
+    /// How long a failed lookup is cached.
+    pub failure_ttl: Duration,
+
+    /// How long expired addresses are still served, while they are refreshed in the background.
+    ///
+    /// Also how long they are kept when the refresh fails.
+    pub stale_ttl: Duration,
+
+    /// Names hit at least this many times during their TTL are refreshed shortly before they
+    /// expire. Zero disables prefetching.
+    pub prefetch_hits: u32,
+}
+
+impl Default for DnsCacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 4096,
+            min_ttl: Duration::from_secs(5),
+            max_ttl: Duration::from_secs(3600),
+            negative_ttl: Duration::from_secs(30),
+            failure_ttl: Duration::from_secs(5),
+            stale_ttl: Duration::from_secs(60),
+            prefetch_hits: 8,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct DnsServerConfig {
+    /// The address the DNS server listens on, changes need a restart.
+    pub bind: SocketAddr,
+
+    /// Domains answered with NXDOMAIN, including their subdomains.
+    pub blocklist: Vec<String>,
+
+    /// TTL of answers whose TTL the resolver does not know.
+    pub ttl: Duration,
+
+    /// How long clients may cache NXDOMAIN and empty answers, advertised with an SOA record.
+    pub negative_ttl: Duration,
+}
+
+impl Default for DnsServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1:53".parse().unwrap(),
+            blocklist: Vec::new(),
+            ttl: Duration::from_secs(60),
+            negative_ttl: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResolverKind {
+    /// The resolver of the operating system, called on a blocking thread.
+    System,
+    /// The built-in asynchronous stub resolver.
+    Stub,
+    /// The built-in stub resolver, querying DNS-over-HTTPS servers.
+    Doh,
+    /// The built-in stub resolver, querying DNS-over-TLS servers.
+    Dot,
+}
+
 impl Config {
     pub fn new(path: &str) -> Result<Self, ConfigError> {
         let pattern = Path::new(path).join("*");
@@ -77,6 +719,22 @@ impl Config {
             .build()?
             .try_deserialize()
     }
+
+    /// Returns whether upstream connections use TCP Fast Open, which `retry` and
+    /// `circuit_breaker` rule out.
+    pub fn fast_open(&self) -> bool {
+        self.connector.fast_open && self.retry.attempts <= 1 && self.circuit_breaker.is_none()
+    }
+
+    /// Warns about settings that are ignored because of others.
+    pub fn warn_ignored(&self) {
+        if self.connector.fast_open && !self.fast_open() {
+            tracing::warn!(
+                "connector.fast_open is ignored while retry attempts or a circuit breaker are \
+                 configured"
+            );
+        }
+    }
 }
 pub fn manager(path: &str) -> Manager {
     let config = Config::new(path).unwrap();
@@ -166,7 +824,11 @@ where
                         kind: notify::EventKind::Modify(_),
                         ..
                     })) => {
-                        *config_clone.write().unwrap() = Config::new(path_clone.as_str()).unwrap();
+                        let mut config = config_clone.write().unwrap();
+                        *config = Config::new(path_clone.as_str()).unwrap();
+                        config.warn_ignored();
+                        // bumped under the lock, so whoever sees it reads the new config
+                        config.generation = GENERATION.fetch_add(1, Ordering::AcqRel) + 1;
                     }
                     Err(_) => break,
                     _ => {}
@@ -185,7 +847,7 @@ where
                 res = &mut task => {
                     match res {
                         Ok(_) => Ok(()),
-                        Err(err) => Err(io::Error::new(io::ErrorKind::Other, format!("watcher task failed: {err}"))),
+                        Err(err) => Err(io::Error::other(format!("watcher task failed: {err}"))),
                     }
                 },
             }
@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use std::{fmt, io};
+
+use futures_util::future::{BoxFuture, FutureExt, Shared};
+use lru::LruCache;
+use tokio::time::Instant;
+use tower_service::Service;
+
+use super::{resolve, Addrs, Name, Resolve};
+
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    /// Maximum number of cached names.
+    pub capacity: NonZeroUsize,
+    /// Lower bound for the TTL of cached addresses.
+    pub min_ttl: Duration,
+    /// Upper bound for the TTL of cached addresses.
+    pub max_ttl: Duration,
+    /// How long a name that does not exist is cached.
+    pub negative_ttl: Duration,
+    /// How long a failed lookup is cached.
+    pub failure_ttl: Duration,
+    /// How long expired addresses are still served while they are refreshed.
+    pub stale_ttl: Duration,
+    /// Hits after which an entry is refreshed shortly before it expires, zero disables.
+    pub prefetch_hits: u32,
+}
+
+/// A resolver caching the answers of an inner resolver.
+///
+/// Addresses are kept for their TTL, clamped to the configured bounds, and failed lookups are
+/// remembered briefly. Concurrent lookups of the same name share a single inner lookup.
+///
+/// Expired addresses are served for up to `stale_ttl` while they are refreshed in the
+/// background, and names hit often are refreshed in the last tenth of their TTL so they never
+/// expire at all.
+#[derive(Clone)]
+pub struct CachingResolver<R> {
+    inner: R,
+    config: Arc<CacheConfig>,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    entries: LruCache<Name, Entry>,
+    inflight: HashMap<Name, Shared<BoxFuture<'static, Entry>>>,
+    stats: CacheStats,
+}
+
+/// Counters of a [`CachingResolver`] since it was built.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    /// Names currently cached.
+    pub entries: usize,
+    pub hits: u64,
+    pub stale_hits: u64,
+    pub misses: u64,
+    pub prefetches: u64,
+}
+
+#[derive(Clone)]
+struct Entry {
+    result: Result<Vec<SocketAddr>, (io::ErrorKind, Arc<str>)>,
+    ttl: Duration,
+    expires: Instant,
+    hits: u32,
+}
+
+impl<R> CachingResolver<R> {
+    pub fn new(inner: R, config: CacheConfig) -> CachingResolver<R> {
+        CachingResolver {
+            inner,
+            state: Arc::new(Mutex::new(State {
+                entries: LruCache::new(config.capacity),
+                inflight: HashMap::new(),
+                stats: CacheStats::default(),
+            })),
+            config: Arc::new(config),
+        }
+    }
+
+    /// Returns a snapshot of the counters.
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        CacheStats {
+            entries: state.entries.len(),
+            ..state.stats
+        }
+    }
+}
+
+impl<R> CachingResolver<R>
+where
+    R: Resolve<Addrs = Addrs, Error = io::Error> + Clone + Send + 'static,
+    R::Future: Send,
+{
+    fn lookup(&self, name: Name) -> Shared<BoxFuture<'static, Entry>> {
+        let mut inner = self.inner.clone();
+        let config = self.config.clone();
+        let state = self.state.clone();
+
+        async move {
+            let result = resolve(&mut inner, name.clone()).await;
+            let entry = Entry::new(result, &config);
+
+            let mut state = state.lock().unwrap();
+            state.inflight.remove(&name);
+
+            // a refresh that failed keeps serving the stale addresses until they are too old
+            let now = Instant::now();
+            match state.entries.get(&name) {
+                Some(stale) if entry.is_failure() && stale.is_servable(now, &config) => {
+                    stale.clone()
+                }
+                _ => {
+                    state.entries.put(name, entry.clone());
+                    entry
+                }
+            }
+        }
+        .boxed()
+        .shared()
+    }
+
+    /// Starts a lookup of `name` unless one is in flight.
+    fn refresh(&self, state: &mut State, name: &Name) -> Shared<BoxFuture<'static, Entry>> {
+        match state.inflight.get(name) {
+            Some(lookup) => lookup.clone(),
+            None => {
+                let lookup = self.lookup(name.clone());
+                state.inflight.insert(name.clone(), lookup.clone());
+                lookup
+            }
+        }
+    }
+
+    /// Refreshes `name` in the background.
+    fn refresh_in_background(&self, state: &mut State, name: &Name) {
+        if !state.inflight.contains_key(name) {
+            tokio::spawn(self.refresh(state, name));
+        }
+    }
+}
+
+impl<R> Service<Name> for CachingResolver<R>
+where
+    R: Resolve<Addrs = Addrs, Error = io::Error> + Clone + Send + 'static,
+    R::Future: Send,
+{
+    type Response = Addrs;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Addrs, io::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let name = Name::new(name.as_str().to_ascii_lowercase().into());
+        let now = Instant::now();
+
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        if let Some(entry) = state.entries.get_mut(&name) {
+            if entry.expires > now {
+                tracing::trace!("dns cache hit for {}", name);
+                state.stats.hits += 1;
+                entry.hits = entry.hits.saturating_add(1);
+
+                let result = entry.to_result(now);
+                if entry.should_prefetch(now, &self.config) {
+                    tracing::trace!("prefetching {}", name);
+                    state.stats.prefetches += 1;
+                    self.refresh_in_background(state, &name);
+                }
+
+                return Box::pin(async move { result });
+            }
+
+            if entry.is_servable(now, &self.config) {
+                tracing::trace!("dns cache stale hit for {}", name);
+                state.stats.stale_hits += 1;
+
+                let result = entry.to_result(now);
+                self.refresh_in_background(state, &name);
+
+                return Box::pin(async move { result });
+            }
+        }
+
+        tracing::trace!("dns cache miss for {}", name);
+        state.stats.misses += 1;
+        let lookup = self.refresh(state, &name);
+
+        Box::pin(async move { lookup.await.to_result(Instant::now()) })
+    }
+}
+
+impl<R> fmt::Debug for CachingResolver<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("CachingResolver")
+    }
+}
+
+impl Entry {
+    fn new(result: Result<Addrs, io::Error>, config: &CacheConfig) -> Entry {
+        let now = Instant::now();
+
+        match result {
+            Ok(addrs) => {
+                // resolvers that do not know the TTL get the shortest allowed one
+                let ttl = addrs
+                    .ttl()
+                    .unwrap_or(config.min_ttl)
+                    .clamp(config.min_ttl, config.max_ttl.max(config.min_ttl));
+
+                Entry {
+                    result: Ok(addrs.collect()),
+                    ttl,
+                    expires: now + ttl,
+                    hits: 0,
+                }
+            }
+            Err(e) => {
+                let ttl = if e.kind() == io::ErrorKind::NotFound {
+                    config.negative_ttl
+                } else {
+                    config.failure_ttl
+                };
+
+                Entry {
+                    result: Err((e.kind(), e.to_string().into())),
+                    ttl,
+                    expires: now + ttl,
+                    hits: 0,
+                }
+            }
+        }
+    }
+
+    /// Whether the lookup failed for another reason than the name not existing.
+    fn is_failure(&self) -> bool {
+        matches!(&self.result, Err((kind, _)) if *kind != io::ErrorKind::NotFound)
+    }
+
+    /// Whether the entry may still be served, possibly stale.
+    fn is_servable(&self, now: Instant, config: &CacheConfig) -> bool {
+        self.result.is_ok() && now < self.expires + config.stale_ttl
+    }
+
+    /// Whether the entry is hit often enough to be refreshed before it expires.
+    fn should_prefetch(&self, now: Instant, config: &CacheConfig) -> bool {
+        config.prefetch_hits > 0
+            && self.result.is_ok()
+            && self.hits >= config.prefetch_hits
+            && self.expires.saturating_duration_since(now) < self.ttl / 10
+    }
+
+    fn to_result(&self, now: Instant) -> Result<Addrs, io::Error> {
+        match &self.result {
+            Ok(addrs) => Ok(Addrs::new(
+                addrs.clone(),
+                Some(self.expires.saturating_duration_since(now)),
+            )),
+            Err((kind, msg)) => Err(io::Error::new(*kind, msg.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(10);
+    const LOOKUP: Duration = Duration::from_secs(1);
+
+    /// A resolver answering after [`LOOKUP`] with the number of the lookup as the port.
+    #[derive(Clone, Default)]
+    struct Counting {
+        lookups: Arc<AtomicU16>,
+        fail: Arc<AtomicBool>,
+    }
+
+    impl Counting {
+        fn lookups(&self) -> u16 {
+            self.lookups.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Service<Name> for Counting {
+        type Response = Addrs;
+        type Error = io::Error;
+        type Future = BoxFuture<'static, Result<Addrs, io::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _name: Name) -> Self::Future {
+            let lookup = self.lookups.fetch_add(1, Ordering::SeqCst) + 1;
+            let fail = self.fail.load(Ordering::SeqCst);
+
+            async move {
+                tokio::time::sleep(LOOKUP).await;
+                if fail {
+                    return Err(io::Error::other("upstream down"));
+                }
+                let addr = SocketAddr::from(([192, 0, 2, 1], lookup));
+                Ok(Addrs::new(vec![addr], Some(TTL)))
+            }
+            .boxed()
+        }
+    }
+
+    fn cache(prefetch_hits: u32) -> (CachingResolver<Counting>, Counting) {
+        let inner = Counting::default();
+        let config = CacheConfig {
+            capacity: NonZeroUsize::new(16).unwrap(),
+            min_ttl: Duration::from_secs(1),
+            max_ttl: Duration::from_secs(3600),
+            negative_ttl: Duration::from_secs(5),
+            failure_ttl: Duration::from_secs(5),
+            stale_ttl: Duration::from_secs(30),
+            prefetch_hits,
+        };
+        (CachingResolver::new(inner.clone(), config), inner)
+    }
+
+    /// Resolves the test name and returns the lookup that answered it.
+    async fn answer(cache: &CachingResolver<Counting>) -> io::Result<u16> {
+        let name = Name::new("example.com".into());
+        let mut addrs = resolve(&mut cache.clone(), name).await?;
+        Ok(addrs.next().unwrap().port())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn coalesces_concurrent_lookups() {
+        let (cache, inner) = cache(0);
+
+        let (a, b) = tokio::join!(answer(&cache), answer(&cache));
+        assert_eq!((a.unwrap(), b.unwrap()), (1, 1));
+        assert_eq!(inner.lookups(), 1);
+
+        assert_eq!(answer(&cache).await.unwrap(), 1);
+        assert_eq!(inner.lookups(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn serves_stale_while_refreshing() {
+        let (cache, inner) = cache(0);
+        assert_eq!(answer(&cache).await.unwrap(), 1);
+
+        tokio::time::advance(TTL).await;
+        let start = Instant::now();
+        assert_eq!(answer(&cache).await.unwrap(), 1);
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        tokio::time::sleep(2 * LOOKUP).await;
+        assert_eq!(inner.lookups(), 2);
+        assert_eq!(answer(&cache).await.unwrap(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_refresh_keeps_stale_addresses_until_too_old() {
+        let (cache, inner) = cache(0);
+        assert_eq!(answer(&cache).await.unwrap(), 1);
+        inner.fail.store(true, Ordering::SeqCst);
+
+        tokio::time::advance(TTL).await;
+        assert_eq!(answer(&cache).await.unwrap(), 1);
+        tokio::time::sleep(2 * LOOKUP).await;
+        assert_eq!(answer(&cache).await.unwrap(), 1);
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(answer(&cache).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn prefetches_hot_names_before_they_expire() {
+        let (cache, inner) = cache(2);
+        assert_eq!(answer(&cache).await.unwrap(), 1);
+        assert_eq!(answer(&cache).await.unwrap(), 1);
+
+        // the second hit, in the last tenth of the TTL
+        tokio::time::advance(TTL - LOOKUP / 2).await;
+        assert_eq!(answer(&cache).await.unwrap(), 1);
+        tokio::time::sleep(2 * LOOKUP).await;
+        assert_eq!(inner.lookups(), 2);
+        assert_eq!(answer(&cache).await.unwrap(), 2);
+
+        let stats = cache.stats();
+        assert_eq!(stats.prefetches, 1);
+        assert_eq!(stats.stale_hits, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cold_names_are_not_prefetched() {
+        let (cache, inner) = cache(3);
+        assert_eq!(answer(&cache).await.unwrap(), 1);
+
+        tokio::time::advance(TTL - LOOKUP / 2).await;
+        assert_eq!(answer(&cache).await.unwrap(), 1);
+        tokio::time::sleep(LOOKUP / 4).await;
+        assert_eq!(inner.lookups(), 1);
+    }
+}
@@ -0,0 +1,75 @@
+use std::{fmt, io};
+
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+use http::header::{ACCEPT, CONTENT_TYPE};
+use http::{Method, Request, Uri};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+
+use super::message::Message;
+use super::stub::Upstream;
+use crate::connect::tls::TlsConnector;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Largest response body accepted, the maximum size of a DNS message.
+const MAX_RESPONSE: usize = 64 * 1024;
+
+/// A DNS-over-HTTPS (RFC 8484) server, queried with POST requests over pooled connections.
+pub struct DohUpstream {
+    endpoint: Uri,
+    client: Client<TlsConnector, Full<Bytes>>,
+}
+
+impl DohUpstream {
+    pub fn new(endpoint: Uri, connector: TlsConnector) -> DohUpstream {
+        let client = Client::builder(TokioExecutor::new()).build(connector);
+
+        DohUpstream { endpoint, client }
+    }
+}
+
+impl Upstream for DohUpstream {
+    fn exchange<'a>(&'a self, query: &'a Message) -> BoxFuture<'a, io::Result<Message>> {
+        Box::pin(async move {
+            // the id is always zero so that identical queries are cacheable by HTTP caches
+            let mut wire = query.clone();
+            wire.id = 0;
+
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri(self.endpoint.clone())
+                .header(CONTENT_TYPE, DNS_MESSAGE)
+                .header(ACCEPT, DNS_MESSAGE)
+                .body(Full::new(Bytes::from(wire.encode()?)))
+                .map_err(io::Error::other)?;
+
+            let resp = self.client.request(req).await.map_err(io::Error::other)?;
+            if !resp.status().is_success() {
+                return Err(io::Error::other(format!(
+                    "doh server answered with status {}",
+                    resp.status()
+                )));
+            }
+
+            let body = Limited::new(resp.into_body(), MAX_RESPONSE)
+                .collect()
+                .await
+                .map_err(io::Error::other)?
+                .to_bytes();
+
+            let mut response = Message::decode(&body)?;
+            response.id = query.id;
+
+            Ok(response)
+        })
+    }
+}
+
+impl fmt::Debug for DohUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.endpoint, f)
+    }
+}
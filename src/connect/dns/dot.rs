@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use std::{fmt, io};
+
+use futures_util::future::BoxFuture;
+use http::Uri;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time::Instant;
+
+use super::message::Message;
+use super::stub::{read_framed, write_framed, Upstream};
+use crate::connect::tls::{TlsConnector, TlsStream};
+
+/// How long an unused connection is kept open, as suggested by RFC 7858.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Queries that may be queued for a connection before callers wait.
+const MAX_QUEUED: usize = 64;
+
+type Reply = oneshot::Sender<io::Result<Message>>;
+
+/// Queries awaiting their response, by the id they were sent with.
+type Pending = Arc<StdMutex<HashMap<u16, PendingQuery>>>;
+
+struct PendingQuery {
+    id: u16,
+    reply: Reply,
+    sent: Instant,
+}
+
+/// A DNS-over-TLS (RFC 7858) server.
+///
+/// Queries are pipelined over one persistent connection, which is reopened on demand once the
+/// server closes it. A connection with a query left unanswered for longer than `timeout` is
+/// considered stuck and is replaced by a new one.
+pub struct DotUpstream {
+    addr: SocketAddr,
+    name: String,
+    connector: TlsConnector,
+    timeout: Duration,
+    conn: Mutex<Option<Connection>>,
+}
+
+struct Connection {
+    queries: mpsc::Sender<(Message, Reply)>,
+    pending: Pending,
+}
+
+impl Connection {
+    fn is_usable(&self, timeout: Duration) -> bool {
+        // callers also give up on queries for their own reasons, only the age of a query
+        // tells whether the server stopped answering
+        let now = Instant::now();
+        !self.queries.is_closed()
+            && !self
+                .pending
+                .lock()
+                .unwrap()
+                .values()
+                .any(|query| now.duration_since(query.sent) > timeout)
+    }
+}
+
+impl DotUpstream {
+    /// Creates an upstream at `addr` whose certificate must be valid for `name`, expected to
+    /// answer queries within `timeout`.
+    pub fn new(
+        addr: SocketAddr,
+        name: String,
+        connector: TlsConnector,
+        timeout: Duration,
+    ) -> DotUpstream {
+        DotUpstream {
+            addr,
+            name,
+            connector,
+            timeout,
+            conn: Mutex::new(None),
+        }
+    }
+
+    async fn sender(&self) -> io::Result<mpsc::Sender<(Message, Reply)>> {
+        let mut conn = self.conn.lock().await;
+        match conn.as_ref() {
+            Some(conn) if conn.is_usable(self.timeout) => return Ok(conn.queries.clone()),
+            // dropping the connection closes it
+            Some(_) => tracing::trace!("dot server {:?} stopped answering", self),
+            None => {}
+        }
+        *conn = None;
+
+        let dst = Uri::try_from(self.addr.to_string()).map_err(io::Error::other)?;
+        let stream = self.connector.connect(dst, &self.name).await?;
+        tracing::trace!("connected to dot server {:?}", self);
+
+        let (queries, rx) = mpsc::channel(MAX_QUEUED);
+        let pending = Pending::default();
+        tokio::spawn(run(stream, rx, pending.clone()));
+        *conn = Some(Connection {
+            queries: queries.clone(),
+            pending,
+        });
+
+        Ok(queries)
+    }
+}
+
+impl Upstream for DotUpstream {
+    fn exchange<'a>(&'a self, query: &'a Message) -> BoxFuture<'a, io::Result<Message>> {
+        Box::pin(async move {
+            let (reply, response) = oneshot::channel();
+            self.sender()
+                .await?
+                .send((query.clone(), reply))
+                .await
+                .map_err(|_| closed())?;
+
+            response.await.map_err(|_| closed())?
+        })
+    }
+}
+
+impl fmt::Debug for DotUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}#{}", self.addr, self.name)
+    }
+}
+
+/// Drives one connection, matching responses to queries by message id.
+///
+/// Each query is sent with an id unique on the connection, and the response gets the original
+/// id of the query back.
+async fn run(stream: TlsStream, mut queries: mpsc::Receiver<(Message, Reply)>, pending: Pending) {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut next_id: u16 = rand::random();
+
+    let mut responses = tokio::spawn({
+        let pending = pending.clone();
+        async move {
+            loop {
+                let mut response = match read_framed(&mut reader).await {
+                    Ok(response) => response,
+                    Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                        tracing::trace!("dropping malformed dot response: {}", e);
+                        continue;
+                    }
+                    Err(e) => return e,
+                };
+
+                if let Some(query) = pending.lock().unwrap().remove(&response.id) {
+                    response.id = query.id;
+                    let _ = query.reply.send(Ok(response));
+                }
+            }
+        }
+    });
+
+    let err = loop {
+        let idle = pending.lock().unwrap().is_empty();
+
+        let (query, reply) = tokio::select! {
+            query = queries.recv() => match query {
+                Some(query) => query,
+                None => break io::Error::new(io::ErrorKind::NotConnected, "dot connection dropped"),
+            },
+            err = &mut responses => {
+                break err.unwrap_or_else(io::Error::other);
+            }
+            _ = tokio::time::sleep(IDLE_TIMEOUT), if idle => {
+                break io::Error::new(io::ErrorKind::TimedOut, "dot connection idle");
+            }
+        };
+
+        let mut buf = match query.encode() {
+            Ok(buf) => buf,
+            Err(e) => {
+                let _ = reply.send(Err(e));
+                continue;
+            }
+        };
+
+        let id = {
+            let mut pending = pending.lock().unwrap();
+            while pending.contains_key(&next_id) {
+                next_id = next_id.wrapping_add(1);
+            }
+            let id = next_id;
+            next_id = next_id.wrapping_add(1);
+            pending.insert(
+                id,
+                PendingQuery {
+                    id: query.id,
+                    reply,
+                    sent: Instant::now(),
+                },
+            );
+
+            id
+        };
+
+        buf[..2].copy_from_slice(&id.to_be_bytes());
+        if let Err(e) = write_framed(&mut writer, &buf).await {
+            break e;
+        }
+    };
+
+    responses.abort();
+    tracing::trace!("dot connection closed: {}", err);
+
+    for (_, query) in pending.lock().unwrap().drain() {
+        let _ = query
+            .reply
+            .send(Err(io::Error::new(err.kind(), err.to_string())));
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "dot connection closed")
+}
@@ -0,0 +1,641 @@
+//! A minimal DNS message codec (RFC 1035), covering what the resolvers and the DNS server
+//! need: questions and A, AAAA, CNAME and SOA records. Other records are kept as raw data.
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_OPT: u16 = 41;
+
+pub const CLASS_IN: u16 = 1;
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_FORMERR: u8 = 1;
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_NXDOMAIN: u8 = 3;
+pub const RCODE_NOTIMP: u8 = 4;
+
+pub const OPCODE_QUERY: u8 = 0;
+
+/// Largest UDP message every client accepts, without EDNS(0).
+pub const MIN_UDP_PAYLOAD: u16 = 512;
+
+/// UDP payload size advertised with EDNS(0), as recommended by the DNS flag day 2020.
+pub const EDNS_UDP_PAYLOAD: u16 = 1232;
+
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+const FLAG_RA: u16 = 0x0080;
+
+const MAX_NAME_LEN: usize = 255;
+const MAX_LABEL_LEN: usize = 63;
+const MAX_POINTERS: usize = 32;
+
+#[derive(Clone, Debug, Default)]
+pub struct Message {
+    pub id: u16,
+    pub flags: u16,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+    pub additionals: Vec<Record>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub name: String,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: RData,
+}
+
+#[derive(Clone, Debug)]
+pub enum RData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Cname(String),
+    Soa(Soa),
+    Other(Vec<u8>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Soa {
+    pub mname: String,
+    pub rname: String,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    pub minimum: u32,
+}
+
+impl Message {
+    /// Builds a recursive query for `name`, advertising EDNS(0) support.
+    pub fn query(id: u16, name: &str, qtype: u16) -> Message {
+        Message {
+            id,
+            flags: FLAG_RD,
+            questions: vec![Question {
+                name: name.to_string(),
+                qtype,
+                qclass: CLASS_IN,
+            }],
+            additionals: vec![Record {
+                name: String::new(),
+                rtype: TYPE_OPT,
+                class: EDNS_UDP_PAYLOAD,
+                ttl: 0,
+                data: RData::Other(Vec::new()),
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Builds an empty response to `query`, echoing its id, question and EDNS(0) support.
+    pub fn response_to(query: &Message, rcode: u8) -> Message {
+        let additionals = query
+            .additionals
+            .iter()
+            .filter(|record| record.rtype == TYPE_OPT)
+            .map(|_| Record {
+                name: String::new(),
+                rtype: TYPE_OPT,
+                class: EDNS_UDP_PAYLOAD,
+                ttl: 0,
+                data: RData::Other(Vec::new()),
+            })
+            .take(1)
+            .collect();
+
+        Message {
+            id: query.id,
+            flags: FLAG_QR | (query.flags & (0x7800 | FLAG_RD)) | FLAG_RA | u16::from(rcode & 0x0f),
+            questions: query.questions.clone(),
+            additionals,
+            ..Default::default()
+        }
+    }
+
+    pub fn opcode(&self) -> u8 {
+        ((self.flags >> 11) & 0x0f) as u8
+    }
+
+    /// The largest UDP response the sender of this message accepts.
+    pub fn udp_payload_size(&self) -> u16 {
+        self.additionals
+            .iter()
+            .find(|record| record.rtype == TYPE_OPT)
+            .map_or(MIN_UDP_PAYLOAD, |opt| opt.class.max(MIN_UDP_PAYLOAD))
+    }
+
+    /// Drops the records and marks the message as truncated, so clients retry over TCP.
+    pub fn truncate(&mut self) {
+        self.answers.clear();
+        self.authorities.clear();
+        self.flags |= FLAG_TC;
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.flags & FLAG_QR != 0
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.flags & FLAG_TC != 0
+    }
+
+    pub fn rcode(&self) -> u8 {
+        (self.flags & 0x0f) as u8
+    }
+
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(512);
+
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&self.flags.to_be_bytes());
+        for len in [
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.additionals.len(),
+        ] {
+            let len = u16::try_from(len).map_err(|_| invalid("too many records"))?;
+            buf.extend_from_slice(&len.to_be_bytes());
+        }
+
+        for question in &self.questions {
+            encode_name(&mut buf, &question.name)?;
+            buf.extend_from_slice(&question.qtype.to_be_bytes());
+            buf.extend_from_slice(&question.qclass.to_be_bytes());
+        }
+
+        for record in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+        {
+            record.encode(&mut buf)?;
+        }
+
+        Ok(buf)
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Message> {
+        let mut reader = Reader { buf, pos: 0 };
+
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let qdcount = reader.u16()?;
+        let ancount = reader.u16()?;
+        let nscount = reader.u16()?;
+        let arcount = reader.u16()?;
+
+        let mut questions = Vec::with_capacity(qdcount.min(16).into());
+        for _ in 0..qdcount {
+            questions.push(Question {
+                name: reader.name()?,
+                qtype: reader.u16()?,
+                qclass: reader.u16()?,
+            });
+        }
+
+        let mut records = |count: u16| {
+            (0..count)
+                .map(|_| Record::decode(&mut reader))
+                .collect::<io::Result<Vec<_>>>()
+        };
+        let answers = records(ancount)?;
+        let authorities = records(nscount)?;
+        let additionals = records(arcount)?;
+
+        Ok(Message {
+            id,
+            flags,
+            questions,
+            answers,
+            authorities,
+            additionals,
+        })
+    }
+}
+
+impl Record {
+    fn encode(&self, buf: &mut Vec<u8>) -> io::Result<()> {
+        encode_name(buf, &self.name)?;
+        buf.extend_from_slice(&self.rtype.to_be_bytes());
+        buf.extend_from_slice(&self.class.to_be_bytes());
+        buf.extend_from_slice(&self.ttl.to_be_bytes());
+
+        let len_pos = buf.len();
+        buf.extend_from_slice(&[0, 0]);
+
+        match &self.data {
+            RData::A(addr) => buf.extend_from_slice(&addr.octets()),
+            RData::Aaaa(addr) => buf.extend_from_slice(&addr.octets()),
+            RData::Cname(name) => encode_name(buf, name)?,
+            RData::Soa(soa) => {
+                encode_name(buf, &soa.mname)?;
+                encode_name(buf, &soa.rname)?;
+                for value in [soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum] {
+                    buf.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+            RData::Other(data) => buf.extend_from_slice(data),
+        }
+
+        let len =
+            u16::try_from(buf.len() - len_pos - 2).map_err(|_| invalid("record data too long"))?;
+        buf[len_pos..len_pos + 2].copy_from_slice(&len.to_be_bytes());
+
+        Ok(())
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> io::Result<Record> {
+        let name = reader.name()?;
+        let rtype = reader.u16()?;
+        let class = reader.u16()?;
+        let ttl = reader.u32()?;
+        let len = usize::from(reader.u16()?);
+
+        let end = reader.pos + len;
+        if end > reader.buf.len() {
+            return Err(invalid("record data out of bounds"));
+        }
+
+        let data = match (rtype, len) {
+            (TYPE_A, 4) => RData::A(Ipv4Addr::from(
+                <[u8; 4]>::try_from(reader.bytes(4)?).unwrap(),
+            )),
+            (TYPE_AAAA, 16) => RData::Aaaa(Ipv6Addr::from(
+                <[u8; 16]>::try_from(reader.bytes(16)?).unwrap(),
+            )),
+            (TYPE_CNAME, _) => RData::Cname(reader.name()?),
+            (TYPE_SOA, _) => RData::Soa(Soa {
+                mname: reader.name()?,
+                rname: reader.name()?,
+                serial: reader.u32()?,
+                refresh: reader.u32()?,
+                retry: reader.u32()?,
+                expire: reader.u32()?,
+                minimum: reader.u32()?,
+            }),
+            _ => RData::Other(reader.bytes(len)?.to_vec()),
+        };
+
+        if reader.pos != end {
+            return Err(invalid("record data length mismatch"));
+        }
+
+        Ok(Record {
+            name,
+            rtype,
+            class,
+            ttl,
+            data,
+        })
+    }
+}
+
+fn encode_name(buf: &mut Vec<u8>, name: &str) -> io::Result<()> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.len() + 2 > MAX_NAME_LEN {
+        return Err(invalid("domain name too long"));
+    }
+
+    if !name.is_empty() {
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > MAX_LABEL_LEN {
+                return Err(invalid("invalid domain name label"));
+            }
+            buf.push(label.len() as u8);
+            buf.extend_from_slice(label.as_bytes());
+        }
+    }
+    buf.push(0);
+
+    Ok(())
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| invalid("unexpected end of message"))?;
+        self.pos += len;
+
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    /// Reads a possibly compressed domain name, without the trailing dot.
+    fn name(&mut self) -> io::Result<String> {
+        let mut name = String::new();
+        let mut pos = self.pos;
+        let mut resume = None;
+        let mut pointers = 0;
+
+        loop {
+            let len = *self
+                .buf
+                .get(pos)
+                .ok_or_else(|| invalid("unexpected end of message"))?;
+
+            match len & 0xc0 {
+                0x00 if len == 0 => {
+                    pos += 1;
+                    break;
+                }
+                0x00 => {
+                    let label = self
+                        .buf
+                        .get(pos + 1..pos + 1 + usize::from(len))
+                        .ok_or_else(|| invalid("unexpected end of message"))?;
+                    if !name.is_empty() {
+                        name.push('.');
+                    }
+                    name.push_str(&String::from_utf8_lossy(label));
+                    if name.len() > MAX_NAME_LEN {
+                        return Err(invalid("domain name too long"));
+                    }
+                    pos += 1 + usize::from(len);
+                }
+                0xc0 => {
+                    let low = *self
+                        .buf
+                        .get(pos + 1)
+                        .ok_or_else(|| invalid("unexpected end of message"))?;
+                    pointers += 1;
+                    if pointers > MAX_POINTERS {
+                        return Err(invalid("too many compression pointers"));
+                    }
+                    resume.get_or_insert(pos + 2);
+                    pos = usize::from(u16::from_be_bytes([len & 0x3f, low]));
+                }
+                _ => return Err(invalid("unsupported label type")),
+            }
+        }
+
+        self.pos = resume.unwrap_or(pos);
+
+        Ok(name)
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A response header with one question and `ancount` answers.
+    fn header(ancount: u16) -> Vec<u8> {
+        let mut buf = vec![0x12, 0x34, 0x81, 0x80, 0, 1];
+        buf.extend_from_slice(&ancount.to_be_bytes());
+        buf.extend_from_slice(&[0, 0, 0, 0]);
+        buf
+    }
+
+    /// `header(1)` followed by the question `example.com IN A`, at offset 12.
+    fn with_question() -> Vec<u8> {
+        let mut buf = header(1);
+        buf.extend_from_slice(b"\x07example\x03com\x00");
+        buf.extend_from_slice(&[0, 1, 0, 1]);
+        buf
+    }
+
+    fn record(name: &str, rtype: u16, data: RData) -> Record {
+        Record {
+            name: name.to_string(),
+            rtype,
+            class: CLASS_IN,
+            ttl: 300,
+            data,
+        }
+    }
+
+    #[test]
+    fn query_round_trip() {
+        let query = Message::query(0xbeef, "example.com", TYPE_AAAA);
+        let decoded = Message::decode(&query.encode().unwrap()).unwrap();
+
+        assert_eq!(decoded.id, 0xbeef);
+        assert!(!decoded.is_response());
+        assert_eq!(decoded.opcode(), OPCODE_QUERY);
+        assert_eq!(decoded.questions, query.questions);
+        assert_eq!(decoded.udp_payload_size(), EDNS_UDP_PAYLOAD);
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let query = Message::query(1, "www.example.com", TYPE_A);
+        let mut response = Message::response_to(&query, RCODE_NOERROR);
+        response.answers = vec![
+            record(
+                "www.example.com",
+                TYPE_CNAME,
+                RData::Cname("example.com".into()),
+            ),
+            record("example.com", TYPE_A, RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+            record("example.com", TYPE_AAAA, RData::Aaaa(Ipv6Addr::LOCALHOST)),
+            record("example.com", 16, RData::Other(b"\x05hello".to_vec())),
+        ];
+        response.authorities = vec![record(
+            "example.com",
+            TYPE_SOA,
+            RData::Soa(Soa {
+                mname: "ns.example.com".into(),
+                rname: "hostmaster.example.com".into(),
+                serial: 1,
+                refresh: 2,
+                retry: 3,
+                expire: 4,
+                minimum: 5,
+            }),
+        )];
+
+        let decoded = Message::decode(&response.encode().unwrap()).unwrap();
+
+        assert!(decoded.is_response());
+        assert_eq!(decoded.id, 1);
+        assert_eq!(decoded.rcode(), RCODE_NOERROR);
+        assert_eq!(decoded.questions, query.questions);
+        assert_eq!(decoded.answers.len(), 4);
+        assert!(matches!(&decoded.answers[0].data, RData::Cname(name) if name == "example.com"));
+        assert!(
+            matches!(decoded.answers[1].data, RData::A(ip) if ip == Ipv4Addr::new(192, 0, 2, 1))
+        );
+        assert!(matches!(decoded.answers[2].data, RData::Aaaa(ip) if ip == Ipv6Addr::LOCALHOST));
+        assert!(matches!(&decoded.answers[3].data, RData::Other(data) if data == b"\x05hello"));
+        assert_eq!(decoded.answers[1].ttl, 300);
+        assert!(matches!(
+            &decoded.authorities[0].data,
+            RData::Soa(soa) if soa.rname == "hostmaster.example.com" && soa.minimum == 5
+        ));
+    }
+
+    #[test]
+    fn truncate_keeps_question() {
+        let query = Message::query(1, "example.com", TYPE_A);
+        let mut response = Message::response_to(&query, RCODE_NOERROR);
+        response.answers = vec![record("example.com", TYPE_A, RData::A(Ipv4Addr::LOCALHOST))];
+        response.truncate();
+
+        let decoded = Message::decode(&response.encode().unwrap()).unwrap();
+        assert!(decoded.is_truncated());
+        assert!(decoded.answers.is_empty());
+        assert_eq!(decoded.questions, query.questions);
+    }
+
+    #[test]
+    fn decodes_compressed_names() {
+        let mut buf = with_question();
+        // www + pointer to the question name
+        buf.extend_from_slice(b"\x03www\xc0\x0c");
+        buf.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+
+        let message = Message::decode(&buf).unwrap();
+        assert_eq!(message.answers[0].name, "www.example.com");
+        assert!(
+            matches!(message.answers[0].data, RData::A(ip) if ip == Ipv4Addr::new(192, 0, 2, 1))
+        );
+    }
+
+    #[test]
+    fn rejects_pointer_loops() {
+        let mut buf = with_question();
+        // a pointer to itself
+        let pos = buf.len() as u8;
+        buf.extend_from_slice(&[0xc0, pos]);
+        buf.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+        assert!(Message::decode(&buf).is_err());
+
+        // two pointers to each other
+        let mut buf = with_question();
+        let pos = buf.len() as u8;
+        buf.extend_from_slice(&[0xc0, pos + 2, 0xc0, pos]);
+        buf.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+        assert!(Message::decode(&buf).is_err());
+    }
+
+    #[test]
+    fn rejects_pointers_out_of_bounds() {
+        let mut buf = with_question();
+        buf.extend_from_slice(&[0xc0, 0xff]);
+        buf.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+        assert!(Message::decode(&buf).is_err());
+
+        // a pointer cut off after its first byte
+        let mut buf = with_question();
+        buf.push(0xc0);
+        assert!(Message::decode(&buf).is_err());
+    }
+
+    #[test]
+    fn rejects_reserved_label_types() {
+        let mut buf = header(0);
+        buf.extend_from_slice(&[0x40, 0, 0, 1, 0, 1]);
+        assert!(Message::decode(&buf).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        let buf = Message::query(1, "example.com", TYPE_A).encode().unwrap();
+        for len in 0..buf.len() {
+            assert!(
+                Message::decode(&buf[..len]).is_err(),
+                "decoded {} bytes",
+                len
+            );
+        }
+
+        // a label running past the end
+        let mut buf = header(0);
+        buf.extend_from_slice(b"\x3fexample");
+        assert!(Message::decode(&buf).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_records() {
+        let mut buf = with_question();
+        buf.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0]);
+        assert!(Message::decode(&buf).is_err());
+
+        // more answers announced than present
+        let mut buf = with_question();
+        buf[6..8].copy_from_slice(&2u16.to_be_bytes());
+        buf.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+        assert!(Message::decode(&buf).is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_lengths() {
+        // record data longer than the message
+        let mut buf = with_question();
+        buf.extend_from_slice(&[0xc0, 0x0c, 0, 16, 0, 1, 0, 0, 0, 60, 0xff, 0xff, 1, 2, 3]);
+        assert!(Message::decode(&buf).is_err());
+
+        // a CNAME whose name ends before its record data
+        let mut buf = with_question();
+        buf.extend_from_slice(&[0xc0, 0x0c, 0, 5, 0, 1, 0, 0, 0, 60, 0, 4, 0xc0, 0x0c, 0, 0]);
+        assert!(Message::decode(&buf).is_err());
+
+        // a question name longer than 255 bytes
+        let mut buf = header(0);
+        for _ in 0..5 {
+            buf.push(63);
+            buf.extend_from_slice(&[b'a'; 63]);
+        }
+        buf.push(0);
+        buf.extend_from_slice(&[0, 1, 0, 1]);
+        assert!(Message::decode(&buf).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_names_on_encode() {
+        let long = ["a"; 128].join(".");
+        assert!(Message::query(1, &long, TYPE_A).encode().is_err());
+
+        let label = "a".repeat(64);
+        assert!(Message::query(1, &label, TYPE_A).encode().is_err());
+
+        assert!(Message::query(1, "a..b", TYPE_A).encode().is_err());
+    }
+
+    #[test]
+    fn encodes_absolute_and_root_names() {
+        let query = Message::query(1, "example.com.", TYPE_A);
+        let decoded = Message::decode(&query.encode().unwrap()).unwrap();
+        assert_eq!(decoded.questions[0].name, "example.com");
+
+        let query = Message::query(1, "", TYPE_A);
+        let decoded = Message::decode(&query.encode().unwrap()).unwrap();
+        assert_eq!(decoded.questions[0].name, "");
+    }
+}
@@ -0,0 +1,555 @@
+pub mod cache;
+pub mod doh;
+pub mod dot;
+pub mod message;
+pub mod split;
+pub mod stub;
+
+use std::error::Error;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use std::{fmt, io, vec};
+
+use futures_util::future::BoxFuture;
+use ipnet::Ipv6Net;
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+use tower_service::Service;
+use tracing::debug_span;
+
+pub use cache::CachingResolver;
+pub use doh::DohUpstream;
+pub use dot::DotUpstream;
+pub use split::SplitResolver;
+pub use stub::StubResolver;
+
+#[derive(Clone, Hash, Eq, PartialEq)]
+pub struct Name {
+    host: Box<str>,
+}
+
+impl Name {
+    pub fn new(host: Box<str>) -> Name {
+        Name { host }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.host
+    }
+}
+
+impl fmt::Debug for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.host, f)
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.host, f)
+    }
+}
+
+impl FromStr for Name {
+    type Err = InvalidNameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Name::new(s.into()))
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidNameError(());
+
+impl fmt::Display for InvalidNameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Not a valid domain name")
+    }
+}
+
+impl Error for InvalidNameError {}
+
+pub struct Addrs {
+    inner: SocketAddrs,
+    ttl: Option<Duration>,
+}
+
+impl Addrs {
+    pub fn new(addrs: Vec<SocketAddr>, ttl: Option<Duration>) -> Addrs {
+        Addrs {
+            inner: SocketAddrs::new(addrs),
+            ttl,
+        }
+    }
+
+    /// How long the addresses may be cached, if the resolver knows.
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+}
+
+impl Iterator for Addrs {
+    type Item = SocketAddr;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+impl fmt::Debug for Addrs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Addrs").finish()
+    }
+}
+
+/// Which address families upstream connections use, and which one is tried first.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IpPreference {
+    Ipv4Only,
+    Ipv6Only,
+    PreferIpv4,
+    PreferIpv6,
+    /// Tries the family of the first resolved address first.
+    #[default]
+    System,
+}
+
+#[derive(Clone)]
+pub struct SocketAddrs {
+    iter: vec::IntoIter<SocketAddr>,
+}
+
+impl SocketAddrs {
+    pub fn new(addrs: Vec<SocketAddr>) -> SocketAddrs {
+        SocketAddrs {
+            iter: addrs.into_iter(),
+        }
+    }
+
+    pub fn try_parse(host: &str, port: u16) -> Option<SocketAddrs> {
+        if let Ok(addr) = host.parse::<Ipv4Addr>() {
+            let addr = SocketAddrV4::new(addr, port);
+            return Some(SocketAddrs {
+                iter: vec![SocketAddr::V4(addr)].into_iter(),
+            });
+        }
+        if let Ok(addr) = host.parse::<Ipv6Addr>() {
+            let addr = SocketAddrV6::new(addr, port, 0, 0);
+            return Some(SocketAddrs {
+                iter: vec![SocketAddr::V6(addr)].into_iter(),
+            });
+        }
+        None
+    }
+
+    /// Drops the addresses of a family excluded by `preference`.
+    pub fn filter_by_preference(self, preference: IpPreference) -> SocketAddrs {
+        match preference {
+            IpPreference::Ipv4Only => self.filter(SocketAddr::is_ipv4),
+            IpPreference::Ipv6Only => self.filter(SocketAddr::is_ipv6),
+            _ => self,
+        }
+    }
+
+    pub fn split_by_preference(
+        self,
+        local_addr_ipv4: Option<Ipv4Addr>,
+        local_addr_ipv6: Option<Ipv6Addr>,
+        preference: IpPreference,
+    ) -> (SocketAddrs, SocketAddrs) {
+        match (local_addr_ipv4, local_addr_ipv6) {
+            (Some(_), None) => (self.filter(SocketAddr::is_ipv4), SocketAddrs::new(vec![])),
+            (None, Some(_)) => (self.filter(SocketAddr::is_ipv6), SocketAddrs::new(vec![])),
+            _ => {
+                let preferring_v6 = match preference {
+                    IpPreference::Ipv6Only | IpPreference::PreferIpv6 => true,
+                    IpPreference::Ipv4Only | IpPreference::PreferIpv4 => false,
+                    IpPreference::System => self
+                        .iter
+                        .as_slice()
+                        .first()
+                        .map(SocketAddr::is_ipv6)
+                        .unwrap_or(false),
+                };
+
+                let (preferred, fallback) = self
+                    .iter
+                    .partition::<Vec<_>, _>(|addr| addr.is_ipv6() == preferring_v6);
+
+                (SocketAddrs::new(preferred), SocketAddrs::new(fallback))
+            }
+        }
+    }
+
+    /// Orders the addresses for staggered connection attempts (RFC 8305), alternating between
+    /// the preferred and the other family.
+    pub fn interleave_by_preference(
+        self,
+        local_addr_ipv4: Option<Ipv4Addr>,
+        local_addr_ipv6: Option<Ipv6Addr>,
+        preference: IpPreference,
+    ) -> SocketAddrs {
+        let (mut preferred, mut fallback) =
+            self.split_by_preference(local_addr_ipv4, local_addr_ipv6, preference);
+
+        let mut addrs = Vec::with_capacity(preferred.len() + fallback.len());
+        loop {
+            match (preferred.next(), fallback.next()) {
+                (None, None) => break,
+                (a, b) => addrs.extend(a.into_iter().chain(b)),
+            }
+        }
+
+        SocketAddrs::new(addrs)
+    }
+
+    /// Synthesizes IPv6 addresses within the NAT64 `prefix` for IPv4 addresses (RFC 6147), unless
+    /// there already are IPv6 addresses.
+    pub fn synthesize_nat64(self, prefix: Ipv6Net) -> io::Result<SocketAddrs> {
+        if self.iter.as_slice().iter().any(SocketAddr::is_ipv6) {
+            return Ok(self);
+        }
+
+        self.iter
+            .map(|addr| match addr {
+                SocketAddr::V4(v4) => nat64(prefix, *v4.ip())
+                    .map(|ip| SocketAddr::V6(SocketAddrV6::new(ip, v4.port(), 0, 0)))
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("invalid NAT64 prefix length: {}", prefix),
+                        )
+                    }),
+                addr => Ok(addr),
+            })
+            .collect::<io::Result<_>>()
+            .map(SocketAddrs::new)
+    }
+
+    #[inline]
+    fn filter(self, predicate: impl FnMut(&SocketAddr) -> bool) -> SocketAddrs {
+        SocketAddrs::new(self.iter.filter(predicate).collect())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter.as_slice().is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter.as_slice().len()
+    }
+}
+
+/// Embeds `addr` into the NAT64 `prefix` as described in RFC 6052, skipping bits 64 to 71.
+fn nat64(prefix: Ipv6Net, addr: Ipv4Addr) -> Option<Ipv6Addr> {
+    if !matches!(prefix.prefix_len(), 32 | 40 | 48 | 56 | 64 | 96) {
+        return None;
+    }
+
+    let mut octets = prefix.trunc().addr().octets();
+    let mut pos = usize::from(prefix.prefix_len() / 8);
+    for octet in addr.octets() {
+        if pos == 8 {
+            pos += 1;
+        }
+        octets[pos] = octet;
+        pos += 1;
+    }
+
+    Some(Ipv6Addr::from(octets))
+}
+
+impl Iterator for SocketAddrs {
+    type Item = SocketAddr;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next()
+    }
+}
+
+#[derive(Clone)]
+pub struct Resolver {
+    _priv: (),
+}
+
+impl Resolver {
+    pub fn new() -> Resolver {
+        Resolver { _priv: () }
+    }
+}
+
+impl Service<Name> for Resolver {
+    type Response = Addrs;
+    type Error = io::Error;
+    type Future = ResolverFuture;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let span = debug_span!("resolve", host = %name.host);
+        let blocking = tokio::task::spawn_blocking(move || {
+            let _enter = span.enter();
+            (&*name.host, 0)
+                .to_socket_addrs()
+                .map(|i| SocketAddrs { iter: i })
+        });
+
+        ResolverFuture(blocking)
+    }
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("Resolver")
+    }
+}
+
+pub struct ResolverFuture(JoinHandle<Result<SocketAddrs, io::Error>>);
+
+impl Future for ResolverFuture {
+    type Output = Result<Addrs, io::Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx).map(|res| match res {
+            Ok(Ok(addrs)) => Ok(Addrs {
+                inner: addrs,
+                ttl: None,
+            }),
+            Ok(Err(e)) => Err(e),
+            Err(join_err) => {
+                if join_err.is_cancelled() {
+                    Err(io::Error::new(io::ErrorKind::Interrupted, join_err))
+                } else {
+                    panic!("resolver background task failed: {:?}", join_err)
+                }
+            }
+        })
+    }
+}
+
+impl fmt::Debug for ResolverFuture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("ResolverFuture")
+    }
+}
+
+impl Drop for ResolverFuture {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Resolves every name to a fixed list of addresses, used to reach encrypted upstreams without
+/// asking the local resolver for their addresses.
+#[derive(Clone, Debug)]
+pub struct StaticResolver {
+    addrs: Arc<[IpAddr]>,
+}
+
+impl StaticResolver {
+    pub fn new(addrs: Vec<IpAddr>) -> StaticResolver {
+        StaticResolver {
+            addrs: addrs.into(),
+        }
+    }
+}
+
+impl Service<Name> for StaticResolver {
+    type Response = Addrs;
+    type Error = io::Error;
+    type Future = std::future::Ready<Result<Addrs, io::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _name: Name) -> Self::Future {
+        let addrs = self
+            .addrs
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, 0))
+            .collect();
+
+        std::future::ready(Ok(Addrs::new(addrs, None)))
+    }
+}
+
+/// A type-erased resolver, so the resolver stack can be chosen at runtime.
+#[derive(Clone)]
+pub struct BoxResolver {
+    inner: Arc<dyn Fn(Name) -> BoxFuture<'static, Result<Addrs, io::Error>> + Send + Sync>,
+}
+
+impl BoxResolver {
+    pub fn new<R>(resolver: R) -> BoxResolver
+    where
+        R: Resolve<Addrs = Addrs, Error = io::Error> + Clone + Send + Sync + 'static,
+        R::Future: Send,
+    {
+        BoxResolver {
+            inner: Arc::new(move |name| {
+                let mut resolver = resolver.clone();
+                Box::pin(async move { resolve(&mut resolver, name).await })
+            }),
+        }
+    }
+}
+
+impl Service<Name> for BoxResolver {
+    type Response = Addrs;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Addrs, io::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        (self.inner)(name)
+    }
+}
+
+impl fmt::Debug for BoxResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad("BoxResolver")
+    }
+}
+
+pub trait Resolve {
+    type Addrs: Iterator<Item = SocketAddr>;
+    type Error: Into<Box<dyn Error + Send + Sync>>;
+    type Future: Future<Output = Result<Self::Addrs, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>>;
+    fn resolve(&mut self, name: Name) -> Self::Future;
+}
+
+impl<S> Resolve for S
+where
+    S: Service<Name>,
+    S::Response: Iterator<Item = SocketAddr>,
+    S::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Addrs = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Service::poll_ready(self, cx)
+    }
+
+    fn resolve(&mut self, name: Name) -> Self::Future {
+        Service::call(self, name)
+    }
+}
+
+pub async fn resolve<R>(resolver: &mut R, name: Name) -> Result<R::Addrs, R::Error>
+where
+    R: Resolve,
+{
+    futures_util::future::poll_fn(|cx| resolver.poll_ready(cx)).await?;
+    resolver.resolve(name).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nat64_rfc6052_examples() {
+        // RFC 6052 section 2.4, embedding 192.0.2.33
+        let addr = Ipv4Addr::new(192, 0, 2, 33);
+        for (prefix, expected) in [
+            ("2001:db8::/32", "2001:db8:c000:221::"),
+            ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::/48", "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+            ("2001:db8:122:344::/96", "2001:db8:122:344::192.0.2.33"),
+            ("64:ff9b::/96", "64:ff9b::c000:221"),
+        ] {
+            let prefix: Ipv6Net = prefix.parse().unwrap();
+            let expected: Ipv6Addr = expected.parse().unwrap();
+            assert_eq!(nat64(prefix, addr), Some(expected), "{}", prefix);
+        }
+    }
+
+    #[test]
+    fn nat64_ignores_host_bits_of_prefix() {
+        let prefix: Ipv6Net = "64:ff9b::ffff:ffff/96".parse().unwrap();
+        assert_eq!(
+            nat64(prefix, Ipv4Addr::new(192, 0, 2, 33)),
+            Some("64:ff9b::c000:221".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn nat64_rejects_invalid_prefix_len() {
+        for prefix in [
+            "64:ff9b::/0",
+            "64:ff9b::/33",
+            "64:ff9b::/95",
+            "64:ff9b::/128",
+        ] {
+            let prefix: Ipv6Net = prefix.parse().unwrap();
+            assert_eq!(nat64(prefix, Ipv4Addr::LOCALHOST), None, "{}", prefix);
+        }
+    }
+
+    #[test]
+    fn synthesize_nat64_keeps_ports() {
+        let addrs = SocketAddrs::new(vec![
+            "192.0.2.33:80".parse().unwrap(),
+            "198.51.100.1:443".parse().unwrap(),
+        ]);
+        let addrs: Vec<_> = addrs
+            .synthesize_nat64("64:ff9b::/96".parse().unwrap())
+            .unwrap()
+            .collect();
+        assert_eq!(
+            addrs,
+            [
+                "[64:ff9b::c000:221]:80".parse::<SocketAddr>().unwrap(),
+                "[64:ff9b::c633:6401]:443".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn synthesize_nat64_skips_names_with_ipv6() {
+        let original: Vec<SocketAddr> = vec![
+            "192.0.2.33:80".parse().unwrap(),
+            "[2001:db8::1]:80".parse().unwrap(),
+        ];
+        let addrs: Vec<_> = SocketAddrs::new(original.clone())
+            .synthesize_nat64("64:ff9b::/96".parse().unwrap())
+            .unwrap()
+            .collect();
+        assert_eq!(addrs, original);
+    }
+
+    #[test]
+    fn synthesize_nat64_rejects_invalid_prefix() {
+        let addrs = SocketAddrs::new(vec!["192.0.2.33:80".parse().unwrap()]);
+        let Err(err) = addrs.synthesize_nat64("64:ff9b::/80".parse().unwrap()) else {
+            panic!("expected an error");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let addrs = SocketAddrs::new(Vec::new());
+        assert!(addrs
+            .synthesize_nat64("64:ff9b::/80".parse().unwrap())
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::{fmt, io};
+
+use futures_util::future::BoxFuture;
+use tower_service::Service;
+
+use super::{Addrs, BoxResolver, Name};
+
+/// A resolver answering pinned hosts itself and sending names under configured suffixes to
+/// dedicated resolvers, before falling back to the default one.
+#[derive(Clone)]
+pub struct SplitResolver {
+    hosts: Arc<HashMap<String, Vec<IpAddr>>>,
+    rules: Arc<[(String, BoxResolver)]>,
+    default: BoxResolver,
+}
+
+impl SplitResolver {
+    /// Creates a resolver over `default`.
+    ///
+    /// `hosts` keys and rule suffixes are matched case-insensitively. When several suffixes
+    /// match a name, the longest one wins.
+    pub fn new(
+        hosts: HashMap<String, Vec<IpAddr>>,
+        rules: Vec<(String, BoxResolver)>,
+        default: BoxResolver,
+    ) -> SplitResolver {
+        let hosts = hosts
+            .into_iter()
+            .map(|(host, addrs)| (normalize(&host), addrs))
+            .collect();
+
+        let mut rules: Vec<_> = rules
+            .into_iter()
+            .map(|(suffix, resolver)| (normalize(&suffix), resolver))
+            .collect();
+        rules.sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+
+        SplitResolver {
+            hosts: Arc::new(hosts),
+            rules: rules.into(),
+            default,
+        }
+    }
+
+    fn route(&self, host: &str) -> &BoxResolver {
+        self.rules
+            .iter()
+            .find(|(suffix, _)| {
+                host.strip_suffix(suffix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+            })
+            .map_or(&self.default, |(_, resolver)| resolver)
+    }
+}
+
+impl Service<Name> for SplitResolver {
+    type Response = Addrs;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Addrs, io::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let host = normalize(name.as_str());
+
+        if let Some(addrs) = self.hosts.get(&host) {
+            tracing::trace!("{} pinned to {:?}", host, addrs);
+            let addrs = addrs.iter().map(|ip| SocketAddr::new(*ip, 0)).collect();
+            return Box::pin(async move { Ok(Addrs::new(addrs, None)) });
+        }
+
+        self.route(&host).clone().call(name)
+    }
+}
+
+impl fmt::Debug for SplitResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SplitResolver")
+            .field("hosts", &self.hosts.len())
+            .field("rules", &self.rules.len())
+            .finish()
+    }
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_matches('.').to_ascii_lowercase()
+}
@@ -0,0 +1,386 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use std::{fmt, io};
+
+use futures_util::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tower_service::Service;
+
+use super::message::{Message, RData, RCODE_NOERROR, RCODE_NXDOMAIN, TYPE_A, TYPE_AAAA};
+use super::{Addrs, Name};
+
+/// Largest UDP response accepted, comfortably above the advertised EDNS(0) payload size.
+const MAX_UDP_RESPONSE: usize = 4096;
+
+#[derive(Clone, Debug)]
+pub struct StubConfig {
+    pub search: Vec<String>,
+    pub ndots: usize,
+    pub timeout: Duration,
+    pub attempts: u32,
+}
+
+/// A server recursive queries can be sent to.
+pub trait Upstream: fmt::Debug + Send + Sync {
+    /// Sends `query` and returns the response.
+    fn exchange<'a>(&'a self, query: &'a Message) -> BoxFuture<'a, io::Result<Message>>;
+}
+
+/// An asynchronous stub resolver sending recursive queries to a list of upstreams.
+///
+/// Upstreams are tried in order, starting with the one that answered last, so a server that
+/// stops responding is skipped until the others fail too.
+#[derive(Clone)]
+pub struct StubResolver {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    upstreams: Vec<Arc<dyn Upstream>>,
+    config: StubConfig,
+    preferred: AtomicUsize,
+}
+
+impl StubResolver {
+    pub fn new(upstreams: Vec<Arc<dyn Upstream>>, config: StubConfig) -> StubResolver {
+        StubResolver {
+            inner: Arc::new(Inner {
+                upstreams,
+                config,
+                preferred: AtomicUsize::new(0),
+            }),
+        }
+    }
+}
+
+impl Service<Name> for StubResolver {
+    type Response = Addrs;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, Result<Addrs, io::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            let mut err = None;
+            for fqdn in inner.candidates(name.as_str()) {
+                match inner.lookup(&fqdn).await {
+                    Ok(addrs) => return Ok(addrs),
+                    Err(e) => {
+                        tracing::trace!("lookup of {} failed: {}", fqdn, e);
+                        // keep a failure over a later NotFound, which would be cached
+                        if err
+                            .as_ref()
+                            .is_none_or(|err: &io::Error| err.kind() == io::ErrorKind::NotFound)
+                        {
+                            err = Some(e);
+                        }
+                    }
+                }
+            }
+
+            Err(err.unwrap_or_else(|| not_found(name.as_str())))
+        })
+    }
+}
+
+impl fmt::Debug for StubResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StubResolver")
+            .field("upstreams", &self.inner.upstreams)
+            .finish()
+    }
+}
+
+impl Inner {
+    /// Returns the names to look up for `name`, in order, applying the search list.
+    fn candidates(&self, name: &str) -> Vec<String> {
+        if let Some(name) = name.strip_suffix('.') {
+            return vec![name.to_string()];
+        }
+
+        let searched = self
+            .config
+            .search
+            .iter()
+            .map(|domain| format!("{}.{}", name, domain.trim_matches('.')));
+
+        if name.matches('.').count() >= self.config.ndots {
+            std::iter::once(name.to_string()).chain(searched).collect()
+        } else {
+            searched.chain(std::iter::once(name.to_string())).collect()
+        }
+    }
+
+    async fn lookup(&self, name: &str) -> io::Result<Addrs> {
+        let (v6, v4) = tokio::join!(self.query(name, TYPE_AAAA), self.query(name, TYPE_A));
+
+        let (addrs, ttl) = match (v6, v4) {
+            // NotFound is only definite when neither query failed
+            (Err(a), Err(b)) if a.kind() == io::ErrorKind::NotFound => return Err(b),
+            (Err(e), Err(_)) => return Err(e),
+            // an empty answer for one family says nothing when the other one failed
+            (Ok((addrs, _)), Err(e)) | (Err(e), Ok((addrs, _)))
+                if addrs.is_empty() && e.kind() != io::ErrorKind::NotFound =>
+            {
+                return Err(e);
+            }
+            (Ok(answer), Err(e)) | (Err(e), Ok(answer)) => {
+                tracing::trace!("partial lookup of {}: {}", name, e);
+                answer
+            }
+            (Ok((mut v6, v6_ttl)), Ok((v4, v4_ttl))) => {
+                v6.extend(v4);
+                (v6, min_ttl(v6_ttl, v4_ttl))
+            }
+        };
+
+        if addrs.is_empty() {
+            return Err(not_found(name));
+        }
+
+        let addrs = addrs.into_iter().map(|ip| SocketAddr::new(ip, 0)).collect();
+        Ok(Addrs::new(
+            addrs,
+            ttl.map(|ttl| Duration::from_secs(ttl.into())),
+        ))
+    }
+
+    async fn query(&self, name: &str, qtype: u16) -> io::Result<(Vec<IpAddr>, Option<u32>)> {
+        let len = self.upstreams.len();
+        let preferred = self.preferred.load(Ordering::Relaxed);
+        let mut err = None;
+
+        for _ in 0..self.config.attempts.max(1) {
+            for index in (preferred..preferred + len).map(|i| i % len) {
+                let upstream = &self.upstreams[index];
+                let query = Message::query(rand::random(), name, qtype);
+
+                let response = match tokio::time::timeout(
+                    self.config.timeout,
+                    upstream.exchange(&query),
+                )
+                .await
+                {
+                    Ok(Ok(response)) => validate(&query, &response).map(|_| response),
+                    Ok(Err(e)) => Err(e),
+                    Err(_) => Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "dns query timed out",
+                    )),
+                };
+
+                let response = match response {
+                    Ok(response) => response,
+                    Err(e) => {
+                        tracing::trace!("upstream {:?} failed: {}", upstream, e);
+                        err = Some(e);
+                        continue;
+                    }
+                };
+
+                match response.rcode() {
+                    RCODE_NOERROR => {
+                        self.preferred.store(index, Ordering::Relaxed);
+                        return Ok(answer(&response, qtype));
+                    }
+                    RCODE_NXDOMAIN => {
+                        self.preferred.store(index, Ordering::Relaxed);
+                        return Err(not_found(name));
+                    }
+                    rcode => {
+                        err = Some(io::Error::other(format!(
+                            "upstream {:?} answered with rcode {}",
+                            upstream, rcode
+                        )))
+                    }
+                }
+            }
+        }
+
+        Err(err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "no upstreams configured")
+        }))
+    }
+}
+
+/// A nameserver queried over UDP, falling back to TCP for truncated responses.
+pub struct Nameserver {
+    addr: SocketAddr,
+}
+
+impl Nameserver {
+    pub fn new(addr: SocketAddr) -> Nameserver {
+        Nameserver { addr }
+    }
+
+    async fn exchange_udp(&self, buf: &[u8], id: u16) -> io::Result<Message> {
+        let local: IpAddr = match self.addr {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+
+        let socket = UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+        socket.connect(self.addr).await?;
+        socket.send(buf).await?;
+
+        let mut response = vec![0; MAX_UDP_RESPONSE];
+        loop {
+            let len = socket.recv(&mut response).await?;
+            match Message::decode(&response[..len]) {
+                Ok(message) if message.id == id => return Ok(message),
+                // stray or spoofed datagram, keep waiting for the real answer
+                _ => continue,
+            }
+        }
+    }
+}
+
+impl Upstream for Nameserver {
+    fn exchange<'a>(&'a self, query: &'a Message) -> BoxFuture<'a, io::Result<Message>> {
+        Box::pin(async move {
+            let buf = query.encode()?;
+
+            let response = self.exchange_udp(&buf, query.id).await?;
+            if !response.is_truncated() {
+                return Ok(response);
+            }
+
+            let mut stream = TcpStream::connect(self.addr).await?;
+            exchange_stream(&mut stream, &buf).await
+        })
+    }
+}
+
+impl fmt::Debug for Nameserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.addr, f)
+    }
+}
+
+/// Nameservers and search domains read from a `resolv.conf(5)` file.
+#[derive(Debug, Default)]
+pub struct ResolvConf {
+    pub nameservers: Vec<SocketAddr>,
+    pub search: Vec<String>,
+}
+
+impl ResolvConf {
+    pub fn read(path: impl AsRef<Path>) -> io::Result<ResolvConf> {
+        let mut conf = ResolvConf::default();
+
+        for line in std::fs::read_to_string(path)?.lines() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("nameserver") => {
+                    // link-local addresses may carry a zone, which IpAddr does not parse
+                    let addr = words.next().and_then(|w| w.split('%').next()?.parse().ok());
+                    if let Some(addr) = addr {
+                        conf.nameservers.push(SocketAddr::new(addr, 53));
+                    }
+                }
+                Some("search") | Some("domain") => {
+                    conf.search = words.map(str::to_string).collect();
+                }
+                _ => {}
+            }
+        }
+
+        Ok(conf)
+    }
+}
+
+/// Extracts the addresses of type `qtype` and the smallest TTL along the answer chain.
+pub fn answer(response: &Message, qtype: u16) -> (Vec<IpAddr>, Option<u32>) {
+    let mut addrs = Vec::new();
+    let mut ttl = None;
+
+    for record in &response.answers {
+        match (&record.data, qtype) {
+            (RData::A(addr), TYPE_A) => addrs.push(IpAddr::V4(*addr)),
+            (RData::Aaaa(addr), TYPE_AAAA) => addrs.push(IpAddr::V6(*addr)),
+            (RData::Cname(_), _) => {}
+            _ => continue,
+        }
+        ttl = min_ttl(ttl, Some(record.ttl));
+    }
+
+    (addrs, ttl)
+}
+
+/// Exchanges a query over a stream transport, using the two byte length prefix of RFC 1035.
+pub async fn exchange_stream<S>(stream: &mut S, buf: &[u8]) -> io::Result<Message>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    write_framed(stream, buf).await?;
+    read_framed(stream).await
+}
+
+pub async fn write_framed<S>(stream: &mut S, buf: &[u8]) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let len = u16::try_from(buf.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "dns message too long"))?;
+
+    let mut framed = Vec::with_capacity(buf.len() + 2);
+    framed.extend_from_slice(&len.to_be_bytes());
+    framed.extend_from_slice(buf);
+    stream.write_all(&framed).await?;
+    stream.flush().await
+}
+
+pub async fn read_framed<S>(stream: &mut S) -> io::Result<Message>
+where
+    S: AsyncRead + Unpin,
+{
+    let len = stream.read_u16().await?;
+    let mut buf = vec![0; len.into()];
+    stream.read_exact(&mut buf).await?;
+
+    Message::decode(&buf)
+}
+
+/// Checks that `response` answers `query`.
+fn validate(query: &Message, response: &Message) -> io::Result<()> {
+    let matches = response.id == query.id
+        && response.is_response()
+        && response.questions.len() == query.questions.len()
+        && response
+            .questions
+            .iter()
+            .zip(&query.questions)
+            .all(|(a, b)| a.qtype == b.qtype && a.name.eq_ignore_ascii_case(&b.name));
+
+    if !matches {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "dns response does not match the query",
+        ));
+    }
+
+    Ok(())
+}
+
+fn min_ttl(a: Option<u32>, b: Option<u32>) -> Option<u32> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn not_found(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no addresses found for {}", name),
+    )
+}
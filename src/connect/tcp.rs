@@ -1,30 +1,172 @@
 use std::future::Future;
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Once};
 use std::task::{Context, Poll};
 use std::time::Duration;
 
 use futures_util::future::Either;
+use futures_util::stream::{FuturesUnordered, StreamExt};
 use http::uri::{Scheme, Uri};
 use hyper_util::rt::TokioIo;
-use ipnet::IpNet;
+use ipnet::Ipv6Net;
 use rand::Rng;
+use serde::Deserialize;
+use socket2::SockRef;
 use tokio::net::{TcpSocket, TcpStream};
-use tokio::time::Sleep;
+use tokio::time::{Instant, Sleep};
 use tower_service::Service;
 
-use super::dns::{self, resolve, Resolve, Resolver};
+use super::dns::{self, resolve, IpPreference, Resolve, Resolver};
 use super::error::{DnsError, Error, InvalidUriError, TcpError};
 
+/// How `connect_timeout` applies when a host resolves to several addresses.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectTimeoutMode {
+    /// The timeout is divided evenly between the addresses.
+    #[default]
+    Split,
+    /// Each address gets the full timeout.
+    PerAttempt,
+    /// The timeout bounds the whole connect, however many addresses are tried. Sequential
+    /// attempts each get an equal share of the time left.
+    Total,
+}
+
+/// How the addresses of a host are tried.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectStrategy {
+    /// One address after the other, the other family joins after the happy eyeballs delay.
+    #[default]
+    Sequential,
+    /// Addresses alternate between the families and the next attempt starts after the
+    /// stagger delay or as soon as the previous one failed, without waiting for it to time
+    /// out (RFC 8305).
+    Staggered,
+}
+
+/// TCP keepalive parameters, unset ones keep the system defaults.
+///
+/// `idle` is applied everywhere but on OpenBSD and Haiku. `interval` and `count` are applied
+/// on Linux, Android, macOS, iOS, FreeBSD, NetBSD, DragonFly, illumos, Fuchsia and Windows.
+/// Parameters the platform does not support are skipped with a warning on first use.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TcpKeepalive {
+    /// How long a connection is idle before the first probe.
+    pub idle: Duration,
+    /// Time between probes.
+    pub interval: Option<Duration>,
+    /// Unanswered probes after which the connection is dropped.
+    pub count: Option<u32>,
+}
+
+impl Default for TcpKeepalive {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(60),
+            interval: None,
+            count: None,
+        }
+    }
+}
+
+/// When and how often failed connection attempts are repeated.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first one.
+    pub attempts: u32,
+    /// Pause before the first retry, doubled for every further one.
+    pub backoff: Duration,
+    /// Upper bound for the pause between retries.
+    pub max_backoff: Duration,
+    /// Errors that are worth retrying.
+    pub on: Vec<RetryOn>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 1,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            on: vec![
+                RetryOn::Refused,
+                RetryOn::Reset,
+                RetryOn::TimedOut,
+                RetryOn::Unreachable,
+            ],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// The destination refused the connection.
+    Refused,
+    /// The connection was reset or aborted.
+    Reset,
+    /// The destination did not answer in time.
+    TimedOut,
+    /// No route to the destination.
+    Unreachable,
+}
+
+impl RetryPolicy {
+    /// Returns whether `err` is one of the errors to retry.
+    pub fn is_retriable(&self, err: &io::Error) -> bool {
+        let on = match err.kind() {
+            io::ErrorKind::ConnectionRefused => RetryOn::Refused,
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => RetryOn::Reset,
+            io::ErrorKind::TimedOut => RetryOn::TimedOut,
+            io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::NotConnected => RetryOn::Unreachable,
+            _ => return false,
+        };
+
+        self.on.contains(&on)
+    }
+
+    /// Returns the pause before retry number `retry`, counted from 1.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        self.backoff
+            .saturating_mul(1 << (retry.saturating_sub(1)).min(16))
+            .min(self.max_backoff)
+    }
+}
+
+/// Chooses the local address of a new connection to a host.
+pub type LocalAddressPicker = Arc<dyn Fn(&str) -> io::Result<IpAddr> + Send + Sync>;
+
 #[derive(Clone)]
 struct Config {
     connect_timeout: Option<Duration>,
+    connect_timeout_mode: ConnectTimeoutMode,
+    connect_strategy: ConnectStrategy,
+    stagger_delay: Duration,
     happy_eyeballs_timeout: Option<Duration>,
     local_address_ipv4: Option<Ipv4Addr>,
     local_address_ipv6: Option<Ipv6Addr>,
+    local_address_picker: Option<LocalAddressPicker>,
+    source_port_range: Option<RangeInclusive<u16>>,
+    ip_preference: IpPreference,
+    nat64_prefix: Option<Ipv6Net>,
     nodelay: bool,
+    keepalive: Option<TcpKeepalive>,
+    user_timeout: Option<Duration>,
+    fast_open: bool,
+    retry: RetryPolicy,
+    send_buffer_size: Option<u32>,
+    recv_buffer_size: Option<u32>,
 }
 
 #[derive(Clone)]
@@ -37,6 +179,7 @@ where
 }
 
 impl TcpConnector {
+    #[allow(dead_code)]
     pub fn new() -> Self {
         Self::new_with_resolver(Resolver::new())
     }
@@ -50,29 +193,57 @@ where
         Self {
             config: Arc::new(Config {
                 connect_timeout: None,
+                connect_timeout_mode: ConnectTimeoutMode::Split,
+                connect_strategy: ConnectStrategy::Sequential,
+                stagger_delay: Duration::from_millis(250),
                 happy_eyeballs_timeout: Some(Duration::from_millis(300)),
                 local_address_ipv4: None,
                 local_address_ipv6: None,
+                local_address_picker: None,
+                source_port_range: None,
+                ip_preference: IpPreference::System,
+                nat64_prefix: None,
                 nodelay: false,
+                keepalive: None,
+                user_timeout: None,
+                fast_open: false,
+                retry: RetryPolicy::default(),
+                send_buffer_size: None,
+                recv_buffer_size: None,
             }),
             resolver,
         }
     }
 
     #[inline]
-    #[allow(dead_code)]
     pub fn set_connect_timeout(&mut self, dur: Option<Duration>) {
         self.config_mut().connect_timeout = dur;
     }
 
+    /// Chooses how the connect timeout is shared between the addresses of a host.
+    #[inline]
+    pub fn set_connect_timeout_mode(&mut self, mode: ConnectTimeoutMode) {
+        self.config_mut().connect_timeout_mode = mode;
+    }
+
+    /// Chooses how the addresses of a host are tried.
+    #[inline]
+    pub fn set_connect_strategy(&mut self, strategy: ConnectStrategy) {
+        self.config_mut().connect_strategy = strategy;
+    }
+
+    /// Sets the delay between staggered connection attempts.
+    #[inline]
+    pub fn set_stagger_delay(&mut self, dur: Duration) {
+        self.config_mut().stagger_delay = dur;
+    }
+
     #[inline]
-    #[allow(dead_code)]
     pub fn set_happy_eyeballs_timeout(&mut self, dur: Option<Duration>) {
         self.config_mut().happy_eyeballs_timeout = dur;
     }
 
     #[inline]
-    #[allow(dead_code)]
     pub fn set_local_address(&mut self, addr: Option<IpAddr>) {
         let (v4, v6) = match addr {
             Some(IpAddr::V4(a)) => (Some(a), None),
@@ -95,47 +266,71 @@ where
         cfg.local_address_ipv6 = Some(addr_ipv6);
     }
 
+    /// Lets `picker` choose the local address of each new connection, in place of the one set
+    /// with [`set_local_address`](Self::set_local_address).
     #[inline]
-    #[allow(dead_code)]
-    pub fn set_nodelay(&mut self, nodelay: bool) {
-        self.config_mut().nodelay = nodelay;
+    pub fn set_local_address_picker(&mut self, picker: Option<LocalAddressPicker>) {
+        self.config_mut().local_address_picker = picker;
     }
 
-    pub fn assign_local_address_from_cidr(&mut self, cidr: Option<IpNet>) {
-        if let Some(cidr) = cidr {
-            let mut rng = rand::rng();
+    /// Restricts the source ports of outgoing connections to `range`.
+    #[inline]
+    pub fn set_source_port_range(&mut self, range: Option<RangeInclusive<u16>>) {
+        self.config_mut().source_port_range = range;
+    }
 
-            let addr = match cidr {
-                IpNet::V4(net) => {
-                    let prefix_len = net.prefix_len();
-                    let host_len = 32u8 - prefix_len;
+    /// Restricts and orders the address families of outgoing connections.
+    #[inline]
+    pub fn set_ip_preference(&mut self, preference: IpPreference) {
+        self.config_mut().ip_preference = preference;
+    }
 
-                    let network_bits = net.network().to_bits();
-                    let rand_host_bits = if prefix_len < 31 {
-                        // exclude network address and broadcast address
-                        1 + rng.random_range(0..(1u32 << host_len) - 2)
-                    } else {
-                        // no need to exclude
-                        rng.random_range(0..(1u32 << host_len))
-                    };
+    /// Reaches IPv4 destinations through a NAT64 gateway at `prefix`.
+    #[inline]
+    pub fn set_nat64_prefix(&mut self, prefix: Option<Ipv6Net>) {
+        self.config_mut().nat64_prefix = prefix;
+    }
 
-                    IpAddr::V4(Ipv4Addr::from_bits(network_bits | rand_host_bits))
-                }
-                IpNet::V6(net) => {
-                    let prefix_len = net.prefix_len();
-                    let host_len = 128u8 - prefix_len;
+    #[inline]
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.config_mut().nodelay = nodelay;
+    }
 
-                    let network_bits = net.network().to_bits();
-                    let rand_host_bits = rng.random_range(0..(1u128 << host_len));
+    /// Enables keepalive probes on outgoing connections.
+    #[inline]
+    pub fn set_keepalive(&mut self, keepalive: Option<TcpKeepalive>) {
+        self.config_mut().keepalive = keepalive;
+    }
 
-                    IpAddr::V6(Ipv6Addr::from_bits(network_bits | rand_host_bits))
-                }
-            };
+    /// Sets `TCP_USER_TIMEOUT` of outgoing connections, only supported on Linux, Android and
+    /// Fuchsia.
+    #[inline]
+    pub fn set_user_timeout(&mut self, dur: Option<Duration>) {
+        self.config_mut().user_timeout = dur;
+    }
 
-            tracing::trace!("assigning local address: {:?}", addr);
+    /// Sets `TCP_FASTOPEN_CONNECT` on outgoing connections, only supported on Linux.
+    #[inline]
+    pub fn set_fast_open(&mut self, fast_open: bool) {
+        self.config_mut().fast_open = fast_open;
+    }
 
-            self.set_local_address(Some(addr))
-        }
+    /// Retries failed connections according to `policy`.
+    #[inline]
+    pub fn set_retry(&mut self, policy: RetryPolicy) {
+        self.config_mut().retry = policy;
+    }
+
+    /// Sets `SO_SNDBUF` of outgoing connections.
+    #[inline]
+    pub fn set_send_buffer_size(&mut self, size: Option<u32>) {
+        self.config_mut().send_buffer_size = size;
+    }
+
+    /// Sets `SO_RCVBUF` of outgoing connections.
+    #[inline]
+    pub fn set_recv_buffer_size(&mut self, size: Option<u32>) {
+        self.config_mut().recv_buffer_size = size;
     }
 
     fn config_mut(&mut self) -> &mut Config {
@@ -154,16 +349,19 @@ where
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         futures_util::ready!(self.resolver.poll_ready(cx))
-            .map_err(|e| DnsError(io::Error::new(io::ErrorKind::Other, e)))?;
+            .map_err(|e| DnsError(io::Error::other(e)))?;
         Poll::Ready(Ok(()))
     }
 
     fn call(&mut self, dst: Uri) -> Self::Future {
         let mut self_ = self.clone();
         Box::pin(async move {
-            let config = &self_.config;
-
             let (host, port) = get_host_port(&dst)?;
+            if let Some(picker) = self_.config.local_address_picker.clone() {
+                self_.set_local_address(Some(picker(host).map_err(TcpError)?));
+            }
+
+            let config = &self_.config;
             let host = host.trim_start_matches('[').trim_end_matches(']');
 
             let addrs = if let Some(addrs) = dns::SocketAddrs::try_parse(host, port) {
@@ -171,7 +369,7 @@ where
             } else {
                 let addrs = resolve(&mut self_.resolver, dns::Name::new(host.into()))
                     .await
-                    .map_err(|e| DnsError(io::Error::new(io::ErrorKind::Other, e)))?;
+                    .map_err(|e| DnsError(io::Error::other(e)))?;
 
                 let addrs = addrs
                     .map(|mut addr| {
@@ -183,9 +381,40 @@ where
                 dns::SocketAddrs::new(addrs)
             };
 
-            let c = ConnectingTcp::new(addrs, config);
+            let addrs = match config.nat64_prefix {
+                Some(prefix) => addrs.synthesize_nat64(prefix).map_err(TcpError)?,
+                None => addrs,
+            };
 
-            let sock = c.connect().await?;
+            let addrs = addrs.filter_by_preference(config.ip_preference);
+            if addrs.is_empty() {
+                return Err(TcpError(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no {:?} address for {}", config.ip_preference, host),
+                ))
+                .into());
+            }
+
+            let mut retry = 0;
+            let sock = loop {
+                match connect_addrs(addrs.clone(), config).await {
+                    Ok(sock) => break sock,
+                    Err(e)
+                        if retry + 1 < config.retry.attempts && config.retry.is_retriable(&e.0) =>
+                    {
+                        retry += 1;
+                        let backoff = config.retry.backoff(retry);
+                        tracing::debug!(
+                            "connect to {} failed: {}, retrying in {:?}",
+                            host,
+                            e,
+                            backoff
+                        );
+                        tokio::time::sleep(backoff).await;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            };
 
             if let Err(e) = sock.set_nodelay(config.nodelay) {
                 tracing::warn!("tcp set_nodelay error: {:?}", e)
@@ -196,6 +425,98 @@ where
     }
 }
 
+/// Connects to one of `addrs` with the strategy and timeouts of `config`.
+async fn connect_addrs(addrs: dns::SocketAddrs, config: &Config) -> Result<TcpStream, TcpError> {
+    let connect = async {
+        match config.connect_strategy {
+            ConnectStrategy::Sequential => ConnectingTcp::new(addrs, config).connect().await,
+            ConnectStrategy::Staggered => connect_staggered(addrs, config).await,
+        }
+    };
+
+    match (config.connect_timeout_mode, config.connect_timeout) {
+        (ConnectTimeoutMode::Total, Some(dur)) => tokio::time::timeout(dur, connect)
+            .await
+            .unwrap_or_else(|e| Err(TcpError(io::Error::new(io::ErrorKind::TimedOut, e)))),
+        _ => connect.await,
+    }
+}
+
+/// Returns the timeout of a single attempt to one of `addrs` addresses.
+fn attempt_timeout(config: &Config, addrs: usize) -> Option<Duration> {
+    match config.connect_timeout_mode {
+        ConnectTimeoutMode::Split => config
+            .connect_timeout
+            .and_then(|t| t.checked_div(addrs as u32)),
+        ConnectTimeoutMode::PerAttempt => config.connect_timeout,
+        // sequential attempts are sliced by `ConnectingTcpRemote`, staggered ones overlap
+        ConnectTimeoutMode::Total => None,
+    }
+}
+
+/// Connects to `addrs` with staggered, overlapping attempts (RFC 8305).
+async fn connect_staggered(
+    addrs: dns::SocketAddrs,
+    config: &Config,
+) -> Result<TcpStream, TcpError> {
+    let addrs = addrs.interleave_by_preference(
+        config.local_address_ipv4,
+        config.local_address_ipv6,
+        config.ip_preference,
+    );
+    let connect_timeout = attempt_timeout(config, addrs.len());
+    let mut addrs = addrs.peekable();
+
+    let attempt = |addr: SocketAddr| async move {
+        tracing::debug!("connecting to {}", addr);
+        let result = match connect(&addr, config, connect_timeout) {
+            Ok(connecting) => connecting.await,
+            Err(e) => Err(e),
+        };
+        (addr, result)
+    };
+
+    let mut attempts = FuturesUnordered::new();
+    let stagger = tokio::time::sleep(Duration::ZERO);
+    tokio::pin!(stagger);
+
+    let mut err = None;
+    loop {
+        if attempts.is_empty() && addrs.peek().is_none() {
+            break;
+        }
+
+        tokio::select! {
+            () = &mut stagger, if addrs.peek().is_some() => {
+                if let Some(addr) = addrs.next() {
+                    attempts.push(attempt(addr));
+                }
+                stagger.as_mut().reset(Instant::now() + config.stagger_delay);
+            }
+            Some((addr, result)) = attempts.next(), if !attempts.is_empty() => match result {
+                Ok(tcp) => {
+                    tracing::debug!("connected to {}", addr);
+                    return Ok(tcp);
+                }
+                Err(e) => {
+                    tracing::trace!("connect error for {}: {:?}", addr, e);
+                    err = Some(e);
+                    // start the next attempt right away
+                    stagger.as_mut().reset(Instant::now());
+                }
+            },
+        }
+    }
+
+    match err {
+        Some(e) => Err(e),
+        None => Err(TcpError::from(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "Network unreachable",
+        ))),
+    }
+}
+
 struct ConnectingTcp<'a> {
     preferred: ConnectingTcpRemote,
     fallback: Option<ConnectingTcpFallback>,
@@ -205,27 +526,30 @@ struct ConnectingTcp<'a> {
 impl<'a> ConnectingTcp<'a> {
     fn new(remote_addrs: dns::SocketAddrs, config: &'a Config) -> Self {
         if let Some(fallback_timeout) = config.happy_eyeballs_timeout {
-            let (preferred_addrs, fallback_addrs) = remote_addrs
-                .split_by_preference(config.local_address_ipv4, config.local_address_ipv6);
+            let (preferred_addrs, fallback_addrs) = remote_addrs.split_by_preference(
+                config.local_address_ipv4,
+                config.local_address_ipv6,
+                config.ip_preference,
+            );
             if fallback_addrs.is_empty() {
                 return ConnectingTcp {
-                    preferred: ConnectingTcpRemote::new(preferred_addrs, config.connect_timeout),
+                    preferred: ConnectingTcpRemote::new(preferred_addrs, config),
                     fallback: None,
                     config,
                 };
             }
 
             ConnectingTcp {
-                preferred: ConnectingTcpRemote::new(preferred_addrs, config.connect_timeout),
+                preferred: ConnectingTcpRemote::new(preferred_addrs, config),
                 fallback: Some(ConnectingTcpFallback {
                     delay: tokio::time::sleep(fallback_timeout),
-                    remote: ConnectingTcpRemote::new(fallback_addrs, config.connect_timeout),
+                    remote: ConnectingTcpRemote::new(fallback_addrs, config),
                 }),
                 config,
             }
         } else {
             ConnectingTcp {
-                preferred: ConnectingTcpRemote::new(remote_addrs, config.connect_timeout),
+                preferred: ConnectingTcpRemote::new(remote_addrs, config),
                 fallback: None,
                 config,
             }
@@ -278,23 +602,41 @@ struct ConnectingTcpFallback {
 struct ConnectingTcpRemote {
     addrs: dns::SocketAddrs,
     connect_timeout: Option<Duration>,
+    /// End of the `total` connect timeout.
+    deadline: Option<Instant>,
 }
 
 impl ConnectingTcpRemote {
-    fn new(addrs: dns::SocketAddrs, connect_timeout: Option<Duration>) -> Self {
-        let connect_timeout = connect_timeout.and_then(|t| t.checked_div(addrs.len() as u32));
+    fn new(addrs: dns::SocketAddrs, config: &Config) -> Self {
+        let connect_timeout = attempt_timeout(config, addrs.len());
+        let deadline = match config.connect_timeout_mode {
+            ConnectTimeoutMode::Total => config.connect_timeout.map(|t| Instant::now() + t),
+            _ => None,
+        };
 
         Self {
             addrs,
             connect_timeout,
+            deadline,
         }
     }
 
     async fn connect(&mut self, config: &Config) -> Result<TcpStream, TcpError> {
         let mut err = None;
+        let mut remaining = self.addrs.len() as u32;
         for addr in &mut self.addrs {
+            // under a total timeout each address gets its share of what is left, so a
+            // black-holed one does not use up the budget of those after it
+            let connect_timeout = match self.deadline {
+                Some(deadline) => deadline
+                    .saturating_duration_since(Instant::now())
+                    .checked_div(remaining),
+                None => self.connect_timeout,
+            };
+            remaining -= 1;
+
             tracing::debug!("connecting to {}", addr);
-            match connect(&addr, config, self.connect_timeout)?.await {
+            match connect(&addr, config, connect_timeout)?.await {
                 Ok(tcp) => {
                     tracing::debug!("connected to {}", addr);
                     return Ok(tcp);
@@ -328,27 +670,49 @@ fn connect(
 
     match (addr, &config.local_address_ipv4, &config.local_address_ipv6) {
         (SocketAddr::V4(_), Some(addr), _) => {
-            socket
-                .bind(SocketAddr::new((*addr).into(), 0))
-                .map_err(TcpError)?;
+            bind_local_address(&socket, (*addr).into(), config).map_err(TcpError)?;
         }
         (SocketAddr::V6(_), _, Some(addr)) => {
-            socket
-                .bind(SocketAddr::new((*addr).into(), 0))
-                .map_err(TcpError)?;
+            bind_local_address(&socket, (*addr).into(), config).map_err(TcpError)?;
         }
         _ => {
-            if cfg!(windows) {
+            let any: IpAddr = match *addr {
+                SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+            };
+
+            if config.source_port_range.is_some() {
+                bind_local_address(&socket, any, config).map_err(TcpError)?;
+            } else if cfg!(windows) {
                 // Windows requires a socket be bound before calling connect
-                let any: SocketAddr = match *addr {
-                    SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
-                    SocketAddr::V6(_) => ([0, 0, 0, 0, 0, 0, 0, 0], 0).into(),
-                };
-                socket.bind(any).map_err(TcpError)?;
+                socket.bind(SocketAddr::new(any, 0)).map_err(TcpError)?;
             }
         }
     }
 
+    if let Some(size) = config.send_buffer_size {
+        socket.set_send_buffer_size(size).map_err(TcpError)?;
+    }
+    if let Some(size) = config.recv_buffer_size {
+        socket.set_recv_buffer_size(size).map_err(TcpError)?;
+    }
+    if let Some(keepalive) = &config.keepalive {
+        if let Err(e) = set_keepalive(SockRef::from(&socket), keepalive) {
+            tracing::warn!("tcp set keepalive error: {:?}", e);
+        }
+    }
+    if let Some(dur) = config.user_timeout {
+        if let Err(e) = set_user_timeout(SockRef::from(&socket), dur) {
+            tracing::warn!("tcp set TCP_USER_TIMEOUT error: {:?}", e);
+        }
+    }
+    #[cfg(target_os = "linux")]
+    if config.fast_open {
+        if let Err(e) = sys::set_fast_open_connect(&socket) {
+            tracing::warn!("tcp set TCP_FASTOPEN_CONNECT error: {:?}", e);
+        }
+    }
+
     let connect = socket.connect(*addr);
     Ok(async move {
         match connect_timeout {
@@ -363,6 +727,275 @@ fn connect(
     })
 }
 
+/// Enables keepalive probes on `socket`, with the parameters the platform supports.
+fn set_keepalive(socket: SockRef<'_>, keepalive: &TcpKeepalive) -> io::Result<()> {
+    let params = socket2::TcpKeepalive::new().with_time(keepalive.idle);
+
+    #[cfg(any(target_os = "openbsd", target_os = "haiku"))]
+    {
+        static WARNED: Once = Once::new();
+        warn_unsupported(&WARNED, "TCP keepalive idle time");
+    }
+
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "dragonfly",
+        target_os = "illumos",
+        target_os = "fuchsia",
+        target_os = "windows",
+    ))]
+    let params = {
+        let params = match keepalive.interval {
+            Some(interval) => params.with_interval(interval),
+            None => params,
+        };
+        match keepalive.count {
+            Some(count) => params.with_retries(count),
+            None => params,
+        }
+    };
+
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "dragonfly",
+        target_os = "illumos",
+        target_os = "fuchsia",
+        target_os = "windows",
+    )))]
+    if keepalive.interval.is_some() || keepalive.count.is_some() {
+        static WARNED: Once = Once::new();
+        warn_unsupported(&WARNED, "TCP keepalive interval and count");
+    }
+
+    socket.set_tcp_keepalive(&params)
+}
+
+/// Sets `TCP_USER_TIMEOUT` of `socket` where the platform supports it.
+fn set_user_timeout(socket: SockRef<'_>, dur: Duration) -> io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "fuchsia"))]
+    return socket.set_tcp_user_timeout(Some(dur));
+
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "fuchsia")))]
+    {
+        let _ = (socket, dur);
+        static WARNED: Once = Once::new();
+        warn_unsupported(&WARNED, "TCP_USER_TIMEOUT");
+        Ok(())
+    }
+}
+
+/// Warns once per `warned` that `option` is not supported on this platform.
+// unused where every option is supported
+#[allow(dead_code)]
+fn warn_unsupported(warned: &Once, option: &str) {
+    warned.call_once(|| {
+        tracing::warn!(
+            "{} is not supported on this platform, using the system default",
+            option
+        )
+    });
+}
+
+/// Applies keepalive probes and `TCP_USER_TIMEOUT` to the accepted connection `stream`.
+///
+/// Options the platform does not support are skipped with a warning on first use.
+pub fn set_accepted_options(
+    stream: &TcpStream,
+    keepalive: Option<&TcpKeepalive>,
+    user_timeout: Option<Duration>,
+) -> io::Result<()> {
+    if let Some(keepalive) = keepalive {
+        set_keepalive(SockRef::from(stream), keepalive)?;
+    }
+    if let Some(dur) = user_timeout {
+        set_user_timeout(SockRef::from(stream), dur)?;
+    }
+
+    Ok(())
+}
+
+/// Sets the `TCP_FASTOPEN` queue length of the listening `socket`, only supported on Linux.
+pub fn set_listener_fast_open(socket: &TcpSocket, queue_len: u32) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    return sys::set_fast_open(socket, queue_len);
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (socket, queue_len);
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
+
+/// Binds `socket` to the local address `addr` without reserving a source port up front.
+///
+/// Binding to port 0 makes the kernel pick a port that is unique for the local address alone,
+/// which quickly exhausts the ephemeral ports when many connections share a few addresses. On
+/// Linux `IP_BIND_ADDRESS_NO_PORT` defers the port choice to `connect()`, where it only has to
+/// be unique for the full 4-tuple.
+fn bind_local_address(socket: &TcpSocket, addr: IpAddr, config: &Config) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    if let Err(e) = sys::set_bind_address_no_port(socket) {
+        tracing::warn!("tcp set IP_BIND_ADDRESS_NO_PORT error: {:?}", e);
+    }
+
+    let Some(range) = &config.source_port_range else {
+        return socket.bind(SocketAddr::new(addr, 0));
+    };
+
+    if range.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "source port range is empty",
+        ));
+    }
+
+    // let the kernel pick from the range at connect() time when it supports it (Linux 6.3+),
+    // it only picks ports that are also in the system's ephemeral range though
+    #[cfg(target_os = "linux")]
+    if is_ephemeral(range) && sys::set_local_port_range(socket, range).is_ok() {
+        return socket.bind(SocketAddr::new(addr, 0));
+    }
+
+    bind_port_in_range(socket, addr, range)
+}
+
+/// Returns whether `range` lies within the system's ephemeral port range, warning once if not.
+#[cfg(target_os = "linux")]
+fn is_ephemeral(range: &RangeInclusive<u16>) -> bool {
+    let system = sys::local_port_range();
+    if system
+        .as_ref()
+        .is_some_and(|system| system.start() <= range.start() && range.end() <= system.end())
+    {
+        return true;
+    }
+
+    static WARNED: Once = Once::new();
+    WARNED.call_once(|| match system {
+        Some(system) => tracing::warn!(
+            "source port range {:?} is not within the ephemeral port range {:?} of \
+             net.ipv4.ip_local_port_range, binding source ports explicitly",
+            range,
+            system
+        ),
+        None => tracing::warn!(
+            "cannot read net.ipv4.ip_local_port_range, binding source ports explicitly"
+        ),
+    });
+    false
+}
+
+fn bind_port_in_range(
+    socket: &TcpSocket,
+    addr: IpAddr,
+    range: &RangeInclusive<u16>,
+) -> io::Result<()> {
+    const ATTEMPTS: usize = 16;
+
+    let mut rng = rand::rng();
+    let mut err = None;
+    for _ in 0..ATTEMPTS {
+        let port = rng.random_range(range.clone());
+        match socket.bind(SocketAddr::new(addr, port)) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => err = Some(e),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(err.unwrap_or_else(|| io::Error::from(io::ErrorKind::AddrInUse)))
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::fs;
+    use std::io;
+    use std::mem;
+    use std::ops::RangeInclusive;
+    use std::os::fd::AsRawFd;
+    use std::sync::OnceLock;
+
+    use tokio::net::TcpSocket;
+
+    /// `IP_LOCAL_PORT_RANGE` from `linux/in.h`, available since Linux 6.3.
+    const IP_LOCAL_PORT_RANGE: libc::c_int = 51;
+
+    pub fn set_bind_address_no_port(socket: &TcpSocket) -> io::Result<()> {
+        setsockopt(
+            socket,
+            libc::IPPROTO_IP,
+            libc::IP_BIND_ADDRESS_NO_PORT,
+            1 as libc::c_int,
+        )
+    }
+
+    /// Returns the ephemeral port range of the system, read once from
+    /// `net.ipv4.ip_local_port_range`, which applies to IPv6 too.
+    pub fn local_port_range() -> Option<RangeInclusive<u16>> {
+        static RANGE: OnceLock<Option<RangeInclusive<u16>>> = OnceLock::new();
+
+        RANGE
+            .get_or_init(|| {
+                let range = fs::read_to_string("/proc/sys/net/ipv4/ip_local_port_range").ok()?;
+                let mut ports = range.split_whitespace().map(|port| port.parse().ok());
+                Some(ports.next()??..=ports.next()??)
+            })
+            .clone()
+    }
+
+    pub fn set_local_port_range(socket: &TcpSocket, range: &RangeInclusive<u16>) -> io::Result<()> {
+        let value = u32::from(*range.start()) | (u32::from(*range.end()) << 16);
+        setsockopt(socket, libc::IPPROTO_IP, IP_LOCAL_PORT_RANGE, value)
+    }
+
+    pub fn set_fast_open_connect(socket: &impl AsRawFd) -> io::Result<()> {
+        setsockopt(
+            socket,
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN_CONNECT,
+            1 as libc::c_int,
+        )
+    }
+
+    pub fn set_fast_open(socket: &impl AsRawFd, queue_len: u32) -> io::Result<()> {
+        let queue_len = queue_len.min(libc::c_int::MAX as u32) as libc::c_int;
+        setsockopt(socket, libc::IPPROTO_TCP, libc::TCP_FASTOPEN, queue_len)
+    }
+
+    fn setsockopt<T>(
+        socket: &impl AsRawFd,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: T,
+    ) -> io::Result<()> {
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &value as *const T as *const libc::c_void,
+                mem::size_of::<T>() as libc::socklen_t,
+            )
+        };
+
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
 fn get_host_port(dst: &Uri) -> Result<(&str, u16), InvalidUriError> {
     tracing::trace!(
         "Http::connect; scheme={:?}, host={:?}, port={:?}",
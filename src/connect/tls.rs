@@ -0,0 +1,172 @@
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+
+use http::uri::Uri;
+use hyper_util::client::legacy::connect::{Connected, Connection};
+use hyper_util::rt::TokioIo;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, RootCertStore};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tower_service::Service;
+
+use super::dns::BoxResolver;
+use super::tcp::TcpConnector;
+
+/// Picks the local address of each new connection.
+pub type LocalAddress = Arc<dyn Fn() -> Option<IpAddr> + Send + Sync>;
+
+/// A connector dialing TLS connections over [`TcpConnector`], verifying servers against the
+/// bundled web PKI roots.
+#[derive(Clone)]
+pub struct TlsConnector {
+    tcp: TcpConnector<BoxResolver>,
+    tls: tokio_rustls::TlsConnector,
+    local_address: Option<LocalAddress>,
+}
+
+impl TlsConnector {
+    /// Creates a connector negotiating one of `alpn` as the application protocol.
+    pub fn new(tcp: TcpConnector<BoxResolver>, alpn: &[&[u8]]) -> TlsConnector {
+        let mut config = (*client_config()).clone();
+        config.alpn_protocols = alpn.iter().map(|proto| proto.to_vec()).collect();
+
+        TlsConnector {
+            tcp,
+            tls: tokio_rustls::TlsConnector::from(Arc::new(config)),
+            local_address: None,
+        }
+    }
+
+    /// Calls `local_address` for the local address of each new connection.
+    pub fn set_local_address(&mut self, local_address: Option<LocalAddress>) {
+        self.local_address = local_address;
+    }
+
+    /// Connects to `dst` and verifies the server certificate against `server_name`.
+    pub async fn connect(&self, dst: Uri, server_name: &str) -> io::Result<TlsStream> {
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let mut tcp = self.tcp.clone();
+        if let Some(local_address) = &self.local_address {
+            tcp.set_local_address(local_address());
+        }
+
+        futures_util::future::poll_fn(|cx| tcp.poll_ready(cx))
+            .await
+            .map_err(io::Error::other)?;
+        let stream = tcp.call(dst).await.map_err(io::Error::other)?.into_inner();
+
+        let stream = self.tls.connect(server_name, stream).await?;
+
+        Ok(TlsStream(stream))
+    }
+}
+
+impl Service<Uri> for TlsConnector {
+    type Response = TokioIo<TlsStream>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let connector = self.clone();
+        Box::pin(async move {
+            if dst.scheme() != Some(&http::uri::Scheme::HTTPS) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("not an https uri: {}", dst),
+                ));
+            }
+
+            let host = dst
+                .host()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "uri has no host"))?;
+            let server_name = host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string();
+
+            connector.connect(dst, &server_name).await.map(TokioIo::new)
+        })
+    }
+}
+
+/// A client TLS connection over TCP.
+pub struct TlsStream(tokio_rustls::client::TlsStream<TcpStream>);
+
+impl Connection for TlsStream {
+    fn connected(&self) -> Connected {
+        self.0.get_ref().0.connected()
+    }
+}
+
+impl AsyncRead for TlsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.0.is_write_vectored()
+    }
+}
+
+fn client_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+
+    CONFIG
+        .get_or_init(|| {
+            let roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+
+            let config = ClientConfig::builder_with_provider(
+                rustls::crypto::ring::default_provider().into(),
+            )
+            .with_safe_default_protocol_versions()
+            .expect("the ring provider supports the default protocol versions")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+            Arc::new(config)
+        })
+        .clone()
+}
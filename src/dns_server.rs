@@ -0,0 +1,272 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Semaphore;
+
+use crate::config::{Config, DnsServerConfig};
+use crate::connect::dns::message::{
+    Message, RData, Record, Soa, CLASS_IN, OPCODE_QUERY, RCODE_FORMERR, RCODE_NOERROR,
+    RCODE_NOTIMP, RCODE_NXDOMAIN, RCODE_SERVFAIL, TYPE_A, TYPE_AAAA, TYPE_SOA,
+};
+use crate::connect::dns::stub::{read_framed, write_framed};
+use crate::connect::dns::{resolve, Name, SocketAddrs};
+use crate::resolver::Resolvers;
+use crate::serve::is_connection_error;
+
+/// How long a TCP client may stay silent before its connection is closed.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest UDP query accepted.
+const MAX_UDP_QUERY: usize = 4096;
+
+/// Most UDP queries answered at once, further datagrams are dropped.
+const MAX_UDP_INFLIGHT: usize = 1024;
+
+/// Most TCP connections served at once, further ones wait in the listen backlog.
+const MAX_TCP_CONNECTIONS: usize = 256;
+
+/// A DNS server answering A and AAAA queries with the resolver stack used for dialing.
+#[derive(Clone)]
+pub struct DnsServer {
+    config: Arc<RwLock<Config>>,
+    resolvers: Resolvers,
+    inflight: Arc<Semaphore>,
+    connections: Arc<Semaphore>,
+}
+
+impl DnsServer {
+    pub fn new(config: Arc<RwLock<Config>>, resolvers: Resolvers) -> Self {
+        Self {
+            config,
+            resolvers,
+            inflight: Arc::new(Semaphore::new(MAX_UDP_INFLIGHT)),
+            connections: Arc::new(Semaphore::new(MAX_TCP_CONNECTIONS)),
+        }
+    }
+
+    /// Serves queries on `bind` over UDP and TCP, only returns if binding fails.
+    pub async fn run(self, bind: SocketAddr) -> io::Result<()> {
+        let udp = Arc::new(UdpSocket::bind(bind).await?);
+        let tcp = TcpListener::bind(bind).await?;
+
+        tracing::info!("DNS server listening on {}", udp.local_addr()?);
+
+        tokio::join!(self.serve_udp(udp), self.serve_tcp(tcp));
+
+        Ok(())
+    }
+
+    async fn serve_udp(&self, socket: Arc<UdpSocket>) {
+        let mut buf = vec![0; MAX_UDP_QUERY];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                // e.g. an ICMP port unreachable for an earlier response
+                Err(e) if is_connection_error(&e) => {
+                    tracing::trace!("dns receive error: {}", e);
+                    continue;
+                }
+                Err(e) => {
+                    tracing::warn!("dns receive error: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            let Ok(permit) = self.inflight.clone().try_acquire_owned() else {
+                tracing::trace!("dropping dns query from {}, too many in flight", peer);
+                continue;
+            };
+            let Ok(query) = Message::decode(&buf[..len]) else {
+                tracing::trace!("dropping malformed dns query from {}", peer);
+                continue;
+            };
+
+            let server = self.clone();
+            let socket = socket.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                let mut response = server.answer(&query).await;
+
+                let mut bytes = response.encode();
+                if bytes
+                    .as_ref()
+                    .is_ok_and(|bytes| bytes.len() > usize::from(query.udp_payload_size()))
+                {
+                    response.truncate();
+                    bytes = response.encode();
+                }
+
+                match bytes {
+                    Ok(bytes) => {
+                        if let Err(e) = socket.send_to(&bytes, peer).await {
+                            tracing::trace!("dns response to {} failed: {}", peer, e);
+                        }
+                    }
+                    Err(e) => tracing::warn!("failed to encode dns response: {}", e),
+                }
+            });
+        }
+    }
+
+    async fn serve_tcp(&self, listener: TcpListener) {
+        loop {
+            let Ok(permit) = self.connections.clone().acquire_owned().await else {
+                return;
+            };
+            let (stream, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) if is_connection_error(&e) => continue,
+                Err(e) => {
+                    tracing::warn!("dns accept error: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            let server = self.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                if let Err(e) = server.serve_stream(stream).await {
+                    tracing::trace!("dns connection from {} closed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    async fn serve_stream(&self, mut stream: TcpStream) -> io::Result<()> {
+        loop {
+            let query = match tokio::time::timeout(TCP_IDLE_TIMEOUT, read_framed(&mut stream)).await
+            {
+                Ok(Ok(query)) => query,
+                Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Ok(()),
+            };
+
+            let response = self.answer(&query).await;
+            write_framed(&mut stream, &response.encode()?).await?;
+        }
+    }
+
+    async fn answer(&self, query: &Message) -> Message {
+        let (server, dns64, mut resolver) = {
+            let config = self.config.read().unwrap();
+            (
+                config.dns_server.clone().unwrap_or_default(),
+                config.dns.dns64.as_ref().map(|dns64| dns64.prefix),
+                self.resolvers.get(&config),
+            )
+        };
+
+        if query.is_response() {
+            return Message::response_to(query, RCODE_FORMERR);
+        }
+        if query.opcode() != OPCODE_QUERY {
+            return Message::response_to(query, RCODE_NOTIMP);
+        }
+        let [question] = query.questions.as_slice() else {
+            return Message::response_to(query, RCODE_FORMERR);
+        };
+        if question.qclass != CLASS_IN || !matches!(question.qtype, TYPE_A | TYPE_AAAA) {
+            return Message::response_to(query, RCODE_NOTIMP);
+        }
+
+        let name = question.name.trim_end_matches('.').to_ascii_lowercase();
+        if is_blocked(&server, &name) {
+            tracing::debug!("blocked dns query for {}", name);
+            return negative_response(query, RCODE_NXDOMAIN, &server);
+        }
+
+        // questions are fully qualified, the search list must not be applied to them
+        let addrs = match resolve(&mut resolver, Name::new(format!("{}.", name).into())).await {
+            Ok(addrs) => addrs,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return negative_response(query, RCODE_NXDOMAIN, &server);
+            }
+            Err(e) => {
+                tracing::debug!("dns query for {} failed: {}", name, e);
+                return Message::response_to(query, RCODE_SERVFAIL);
+            }
+        };
+
+        let ttl = addrs.ttl().unwrap_or(server.ttl);
+        let ttl = u32::try_from(ttl.as_secs()).unwrap_or(u32::MAX);
+
+        let mut addrs = SocketAddrs::new(addrs.collect());
+        if let (TYPE_AAAA, Some(prefix)) = (question.qtype, dns64) {
+            match addrs.synthesize_nat64(prefix) {
+                Ok(synthesized) => addrs = synthesized,
+                Err(e) => {
+                    tracing::warn!("{}", e);
+                    return Message::response_to(query, RCODE_SERVFAIL);
+                }
+            }
+        }
+
+        let answers: Vec<_> = addrs
+            .filter_map(|addr| match (addr.ip(), question.qtype) {
+                (IpAddr::V4(ip), TYPE_A) => Some(RData::A(ip)),
+                (IpAddr::V6(ip), TYPE_AAAA) => Some(RData::Aaaa(ip)),
+                _ => None,
+            })
+            .map(|data| Record {
+                name: question.name.clone(),
+                rtype: question.qtype,
+                class: CLASS_IN,
+                ttl,
+                data,
+            })
+            .collect();
+
+        if answers.is_empty() {
+            return negative_response(query, RCODE_NOERROR, &server);
+        }
+
+        let mut response = Message::response_to(query, RCODE_NOERROR);
+        response.answers = answers;
+        response
+    }
+}
+
+/// Builds an NXDOMAIN or NODATA response, with an SOA record in the authority section so
+/// clients cache it for `negative_ttl` (RFC 2308).
+fn negative_response(query: &Message, rcode: u8, config: &DnsServerConfig) -> Message {
+    let ttl = u32::try_from(config.negative_ttl.as_secs()).unwrap_or(u32::MAX);
+    let zone = query
+        .questions
+        .first()
+        .map(|question| question.name.clone())
+        .unwrap_or_default();
+
+    let mut response = Message::response_to(query, rcode);
+    response.authorities.push(Record {
+        name: zone,
+        rtype: TYPE_SOA,
+        class: CLASS_IN,
+        ttl,
+        data: RData::Soa(Soa {
+            mname: "localhost".to_string(),
+            rname: "nobody.localhost".to_string(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: ttl,
+        }),
+    });
+
+    response
+}
+
+fn is_blocked(config: &DnsServerConfig, name: &str) -> bool {
+    config.blocklist.iter().any(|domain| {
+        let domain = domain.trim_matches('.').to_ascii_lowercase();
+        !domain.is_empty()
+            && name
+                .strip_suffix(domain.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+    })
+}
@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Number of entries the table may hold before expired blocks are pruned.
+const MIN_PRUNE_LEN: usize = 1024;
+
+/// (egress address, destination domain) pairs the destination has recently blocked.
+#[derive(Clone, Debug, Default)]
+pub struct Blocks {
+    inner: Arc<Mutex<BlocksInner>>,
+}
+
+#[derive(Debug, Default)]
+struct BlocksInner {
+    until: HashMap<(IpAddr, Box<str>), Instant>,
+    prune_len: usize,
+}
+
+impl Blocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Avoids `addr` for `domain` for the next `cooldown`.
+    pub fn block(&self, addr: IpAddr, domain: &str, cooldown: Duration) {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.until.len() >= inner.prune_len.max(MIN_PRUNE_LEN) {
+            inner.until.retain(|_, until| *until > now);
+            inner.prune_len = inner.until.len() * 2;
+        }
+
+        inner
+            .until
+            .insert((addr, domain.to_ascii_lowercase().into()), now + cooldown);
+    }
+
+    pub fn is_blocked(&self, addr: IpAddr, domain: &str) -> bool {
+        let inner = self.inner.lock().unwrap();
+        if inner.until.is_empty() {
+            return false;
+        }
+
+        inner
+            .until
+            .get(&(addr, domain.to_ascii_lowercase().into()))
+            .is_some_and(|until| *until > Instant::now())
+    }
+}
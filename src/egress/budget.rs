@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use tokio::time::Instant;
+
+use crate::config::RateLimitConfig;
+
+/// Number of entries a table may hold before expired windows are pruned.
+const MIN_PRUNE_LEN: usize = 1024;
+
+/// Request budgets of (egress address, destination domain) pairs.
+///
+/// Each pair may be used for `requests` requests per fixed `interval` window.
+#[derive(Clone, Debug, Default)]
+pub struct Budgets {
+    inner: Arc<Mutex<BudgetsInner>>,
+}
+
+#[derive(Debug, Default)]
+struct BudgetsInner {
+    windows: HashMap<(IpAddr, Box<str>), Window>,
+    prune_len: usize,
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    used: u32,
+}
+
+impl Budgets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns whether the budget of `addr` for `domain` has requests left in the current
+    /// window, without taking one.
+    pub fn has_budget(&self, addr: IpAddr, domain: &str, config: &RateLimitConfig) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .windows
+            .get(&(addr, domain.to_ascii_lowercase().into()))
+            .is_none_or(|window| {
+                window.used < config.requests
+                    || Instant::now().duration_since(window.start) >= config.interval
+            })
+    }
+
+    /// Takes one request from the budget of `addr` for `domain`.
+    ///
+    /// Returns `false` if the budget of the current window is already spent.
+    pub fn try_take(&self, addr: IpAddr, domain: &str, config: &RateLimitConfig) -> bool {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.windows.len() >= inner.prune_len.max(MIN_PRUNE_LEN) {
+            inner
+                .windows
+                .retain(|_, window| now.duration_since(window.start) < config.interval);
+            inner.prune_len = inner.windows.len() * 2;
+        }
+
+        let window = inner
+            .windows
+            .entry((addr, domain.to_ascii_lowercase().into()))
+            .or_insert(Window {
+                start: now,
+                used: 0,
+            });
+
+        if now.duration_since(window.start) >= config.interval {
+            window.start = now;
+            window.used = 0;
+        }
+
+        if window.used >= config.requests {
+            tracing::trace!("egress address {} spent its budget for {}", addr, domain);
+            return false;
+        }
+
+        window.used += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn config() -> RateLimitConfig {
+        RateLimitConfig {
+            requests: 2,
+            interval: Duration::from_secs(10),
+        }
+    }
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn spends_the_window_and_refills_after_it() {
+        let budgets = Budgets::new();
+        let config = config();
+        let a = addr("192.0.2.1");
+
+        assert!(budgets.try_take(a, "example.com", &config));
+        assert!(budgets.try_take(a, "example.com", &config));
+        assert!(!budgets.has_budget(a, "example.com", &config));
+        assert!(!budgets.try_take(a, "example.com", &config));
+
+        tokio::time::advance(Duration::from_secs(9)).await;
+        assert!(!budgets.try_take(a, "example.com", &config));
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(budgets.has_budget(a, "example.com", &config));
+        assert!(budgets.try_take(a, "example.com", &config));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn windows_are_per_address_and_domain() {
+        let budgets = Budgets::new();
+        let config = config();
+        let a = addr("192.0.2.1");
+
+        assert!(budgets.try_take(a, "example.com", &config));
+        assert!(budgets.try_take(a, "EXAMPLE.com", &config));
+        assert!(!budgets.try_take(a, "example.com", &config));
+
+        assert!(budgets.try_take(a, "example.org", &config));
+        assert!(budgets.try_take(addr("192.0.2.2"), "example.com", &config));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn has_budget_takes_nothing() {
+        let budgets = Budgets::new();
+        let config = config();
+        let a = addr("192.0.2.1");
+
+        for _ in 0..5 {
+            assert!(budgets.has_budget(a, "example.com", &config));
+        }
+        assert!(budgets.try_take(a, "example.com", &config));
+        assert!(budgets.try_take(a, "example.com", &config));
+        assert!(!budgets.has_budget(a, "example.com", &config));
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use ipnet::IpNet;
+use tokio::sync::Notify;
+
+use super::{pick, EgressError};
+use crate::config::{LeaseConfig, LeasePolicy};
+
+/// Table of egress addresses currently leased to a session.
+#[derive(Clone, Debug, Default)]
+pub struct Leases {
+    inner: Arc<LeasesInner>,
+}
+
+#[derive(Debug, Default)]
+struct LeasesInner {
+    leased: Mutex<HashSet<IpAddr>>,
+    released: Notify,
+}
+
+impl Leases {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Leases a free address of `pool` that passes `accept`.
+    ///
+    /// Returns `Ok(None)` when the pool is exhausted and the policy allows sharing an address.
+    /// The policy only applies while leased addresses pass `accept`, when none does there is
+    /// nothing to wait for and [`EgressError::Unavailable`] is returned right away.
+    pub(super) async fn acquire(
+        &self,
+        pool: IpNet,
+        config: &LeaseConfig,
+        accept: &mut (dyn FnMut(IpAddr) -> bool + Send),
+    ) -> Result<Option<Arc<Lease>>, EgressError> {
+        let deadline = config
+            .wait_timeout
+            .map(|timeout| tokio::time::Instant::now() + timeout);
+
+        loop {
+            // register for release notifications before looking, so none is missed in between
+            let released = self.inner.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            match self.inner.try_lease(pool, accept) {
+                Attempt::Leased(addr) => {
+                    return Ok(Some(Arc::new(Lease {
+                        addr,
+                        leases: self.inner.clone(),
+                    })))
+                }
+                Attempt::Unavailable => return Err(EgressError::Unavailable(pool)),
+                Attempt::Busy => {}
+            }
+
+            match config.policy {
+                LeasePolicy::Reuse => {
+                    tracing::debug!("egress address pool {} exhausted, sharing an address", pool);
+                    return Ok(None);
+                }
+                LeasePolicy::Reject => return Err(EgressError::Exhausted(pool)),
+                LeasePolicy::Wait => match deadline {
+                    Some(deadline) => tokio::time::timeout_at(deadline, released)
+                        .await
+                        .map_err(|_| EgressError::Timeout(pool))?,
+                    None => released.await,
+                },
+            }
+        }
+    }
+}
+
+/// The outcome of an attempt to lease an address.
+enum Attempt {
+    Leased(IpAddr),
+    /// Every address that passes `accept` is leased.
+    Busy,
+    /// No address passes `accept`.
+    Unavailable,
+}
+
+impl LeasesInner {
+    fn try_lease(&self, pool: IpNet, accept: &mut (dyn FnMut(IpAddr) -> bool + Send)) -> Attempt {
+        let mut leased = self.leased.lock().unwrap();
+
+        let mut busy = false;
+        let addr = pick(pool, |addr| {
+            if leased.contains(&addr) {
+                busy = busy || accept(addr);
+                return false;
+            }
+            accept(addr)
+        });
+
+        match addr {
+            Some(addr) => {
+                leased.insert(addr);
+                Attempt::Leased(addr)
+            }
+            None if busy => Attempt::Busy,
+            None => Attempt::Unavailable,
+        }
+    }
+}
+
+/// An address exclusively leased to one session, released when dropped.
+#[derive(Debug)]
+pub(super) struct Lease {
+    pub(super) addr: IpAddr,
+    leases: Arc<LeasesInner>,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.leases.leased.lock().unwrap().remove(&self.addr);
+        self.leases.released.notify_waiters();
+        tracing::trace!("released egress address {}", self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn pool() -> IpNet {
+        "192.0.2.0/30".parse().unwrap()
+    }
+
+    fn config(policy: LeasePolicy, wait_timeout: Option<Duration>) -> LeaseConfig {
+        LeaseConfig {
+            policy,
+            wait_timeout,
+        }
+    }
+
+    #[tokio::test]
+    async fn unavailable_when_no_address_is_accepted() {
+        let leases = Leases::new();
+        let config = config(LeasePolicy::Wait, None);
+
+        let err = leases
+            .acquire(pool(), &config, &mut |_| false)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, EgressError::Unavailable(_)), "{err}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_when_accepted_addresses_are_leased() {
+        let leases = Leases::new();
+        let config = config(LeasePolicy::Wait, Some(Duration::from_secs(1)));
+        let first = leases
+            .acquire(pool(), &config, &mut |_| true)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // only the leased address is accepted, so it is worth waiting for
+        let leased = first.addr;
+        let err = leases
+            .acquire(pool(), &config, &mut |addr| addr == leased)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, EgressError::Timeout(_)), "{err}");
+    }
+
+    /// Leases the one address `only` accepts.
+    async fn lease_only(leases: &Leases, config: &LeaseConfig, only: IpAddr) -> Arc<Lease> {
+        leases
+            .acquire(pool(), config, &mut |addr| addr == only)
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_leases_the_released_address() {
+        let leases = Leases::new();
+        let config = config(LeasePolicy::Wait, None);
+        let only: IpAddr = "192.0.2.1".parse().unwrap();
+        let first = lease_only(&leases, &config, only).await;
+
+        let waiter = tokio::spawn({
+            let leases = leases.clone();
+            let config = config.clone();
+            async move { lease_only(&leases, &config, only).await.addr }
+        });
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert!(!waiter.is_finished());
+
+        drop(first);
+        assert_eq!(waiter.await.unwrap(), only);
+    }
+
+    #[tokio::test]
+    async fn reuse_shares_when_exhausted() {
+        let leases = Leases::new();
+        let config = config(LeasePolicy::Reuse, None);
+        let only: IpAddr = "192.0.2.1".parse().unwrap();
+        let _first = lease_only(&leases, &config, only).await;
+
+        let second = leases
+            .acquire(pool(), &config, &mut |addr| addr == only)
+            .await
+            .unwrap();
+        assert!(second.is_none());
+    }
+
+    #[tokio::test]
+    async fn reject_fails_when_exhausted() {
+        let leases = Leases::new();
+        let config = config(LeasePolicy::Reject, None);
+        let only: IpAddr = "192.0.2.1".parse().unwrap();
+        let first = lease_only(&leases, &config, only).await;
+
+        let err = leases
+            .acquire(pool(), &config, &mut |addr| addr == only)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, EgressError::Exhausted(_)), "{err}");
+
+        // another address is still free
+        let other = leases
+            .acquire(pool(), &config, &mut |_| true)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(other.addr, first.addr);
+    }
+}
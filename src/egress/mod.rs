@@ -0,0 +1,279 @@
+mod block;
+mod budget;
+mod lease;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex, Weak};
+
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use rand::Rng;
+
+pub use block::Blocks;
+pub use budget::Budgets;
+use lease::Lease;
+pub use lease::Leases;
+
+use crate::config::Config;
+
+/// Number of random picks tried before scanning the pool for a suitable address.
+const RANDOM_PICKS: usize = 16;
+
+/// Pools with at most this many host bits are scanned when random picks keep failing.
+const SCAN_HOST_BITS: u8 = 16;
+
+#[derive(thiserror::Error, Debug)]
+pub enum EgressError {
+    #[error("egress address pool {0} is exhausted")]
+    Exhausted(IpNet),
+
+    #[error("timed out waiting for a free egress address in {0}")]
+    Timeout(IpNet),
+
+    #[error("no egress address in {0} is available for this destination")]
+    Unavailable(IpNet),
+}
+
+/// Egress state of a single client connection.
+///
+/// A session remembers the subnet it was placed in, so every upstream connection made on
+/// behalf of the same client comes from the same subnet of `cidr`. When leases are enabled it
+/// also holds on to its leased address for as long as any of its upstream connections is alive.
+#[derive(Debug, Default)]
+pub struct Session {
+    client: Option<SocketAddr>,
+    subnet: Mutex<Option<IpNet>>,
+    lease: tokio::sync::Mutex<Weak<Lease>>,
+}
+
+impl Session {
+    pub fn new(client: SocketAddr) -> Self {
+        Self {
+            client: Some(client),
+            ..Default::default()
+        }
+    }
+
+    /// Picks a local address from `cidr` for the next upstream connection.
+    ///
+    /// Candidate addresses are offered to `accept`, and the first one it takes is returned.
+    /// `accept` must not take anything from the address, leased ones are offered to it too. The
+    /// returned [`Egress`] must be kept alive for the lifetime of the connection, so that a
+    /// leased address is not handed to another session while it is still in use.
+    pub async fn egress(
+        &self,
+        cidr: IpNet,
+        config: &Config,
+        leases: &Leases,
+        accept: &mut (dyn FnMut(IpAddr) -> bool + Send),
+    ) -> Result<Egress, EgressError> {
+        let pool = self.pool(cidr, config.cidr_subnet_len);
+
+        let Some(lease_config) = &config.lease else {
+            return pick(pool, accept)
+                .map(Egress::shared)
+                .ok_or(EgressError::Unavailable(pool));
+        };
+
+        let mut current = self.lease.lock().await;
+        if let Some(lease) = current.upgrade() {
+            if pool.contains(&lease.addr) && accept(lease.addr) {
+                return Ok(Egress::leased(lease));
+            }
+        }
+
+        match leases.acquire(pool, lease_config, accept).await? {
+            Some(lease) => {
+                tracing::trace!("client {:?} leased {}", self.client, lease.addr);
+                *current = Arc::downgrade(&lease);
+                Ok(Egress::leased(lease))
+            }
+            None => pick(pool, accept)
+                .map(Egress::shared)
+                .ok_or(EgressError::Unavailable(pool)),
+        }
+    }
+
+    pub fn client(&self) -> Option<SocketAddr> {
+        self.client
+    }
+
+    /// Returns the part of `cidr` this session draws addresses from.
+    ///
+    /// Without `subnet_len` that is the whole `cidr`. With it, the session settles on a random
+    /// subnet of that prefix length and only the bits inside it are randomized.
+    pub fn pool(&self, cidr: IpNet, subnet_len: Option<u8>) -> IpNet {
+        let Some(subnet_len) = subnet_len else {
+            return cidr;
+        };
+
+        let mut subnet = self.subnet.lock().unwrap();
+        match *subnet {
+            // the cidr may have been changed by a config reload
+            Some(net) if cidr.contains(&net) && net.prefix_len() == subnet_len => net,
+            _ => {
+                let net = random_subnet(cidr, subnet_len);
+                tracing::trace!("client {:?} assigned subnet {}", self.client, net);
+                *subnet = Some(net);
+                net
+            }
+        }
+    }
+}
+
+/// A local address chosen for an upstream connection.
+#[derive(Debug)]
+pub struct Egress {
+    addr: IpAddr,
+    _lease: Option<Arc<Lease>>,
+}
+
+impl Egress {
+    fn shared(addr: IpAddr) -> Self {
+        Self { addr, _lease: None }
+    }
+
+    fn leased(lease: Arc<Lease>) -> Self {
+        Self {
+            addr: lease.addr,
+            _lease: Some(lease),
+        }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+}
+
+/// Picks a random address of `pool` that passes `accept`.
+///
+/// Small pools are scanned in full when the random picks are all rejected.
+pub fn pick(pool: IpNet, mut accept: impl FnMut(IpAddr) -> bool) -> Option<IpAddr> {
+    for _ in 0..RANDOM_PICKS {
+        let addr = random_addr(pool);
+        if accept(addr) {
+            return Some(addr);
+        }
+    }
+
+    if pool.max_prefix_len() - pool.prefix_len() <= SCAN_HOST_BITS {
+        return pool.hosts().find(|addr| accept(*addr));
+    }
+
+    None
+}
+
+/// Returns a random subnet of `cidr` with the given prefix length.
+///
+/// The prefix length is clamped to the range `cidr.prefix_len()..=max_prefix_len()`.
+pub fn random_subnet(cidr: IpNet, prefix_len: u8) -> IpNet {
+    let prefix_len = prefix_len.clamp(cidr.prefix_len(), cidr.max_prefix_len());
+    let subnet_bits = prefix_len - cidr.prefix_len();
+    if subnet_bits == 0 {
+        return cidr.trunc();
+    }
+
+    let mut rng = rand::rng();
+
+    match cidr {
+        IpNet::V4(net) => {
+            let rand_bits = rng.random_range(0..=(u32::MAX >> (32 - subnet_bits)));
+            let network = net.network().to_bits() | (rand_bits << (32 - prefix_len));
+
+            IpNet::V4(Ipv4Net::new(Ipv4Addr::from_bits(network), prefix_len).unwrap())
+        }
+        IpNet::V6(net) => {
+            let rand_bits = rng.random_range(0..=(u128::MAX >> (128 - subnet_bits)));
+            let network = net.network().to_bits() | (rand_bits << (128 - prefix_len));
+
+            IpNet::V6(Ipv6Net::new(Ipv6Addr::from_bits(network), prefix_len).unwrap())
+        }
+    }
+}
+
+/// Returns a random host address of `cidr`.
+pub fn random_addr(cidr: IpNet) -> IpAddr {
+    let mut rng = rand::rng();
+
+    match cidr {
+        IpNet::V4(net) => {
+            let prefix_len = net.prefix_len();
+            let host_len = 32u8 - prefix_len;
+
+            let network_bits = net.network().to_bits();
+            let rand_host_bits = if prefix_len < 31 {
+                // exclude network address and broadcast address
+                1 + rng.random_range(0..(1u32 << host_len) - 2)
+            } else {
+                // no need to exclude
+                rng.random_range(0..(1u32 << host_len))
+            };
+
+            IpAddr::V4(Ipv4Addr::from_bits(network_bits | rand_host_bits))
+        }
+        IpNet::V6(net) => {
+            let prefix_len = net.prefix_len();
+            let host_len = 128u8 - prefix_len;
+
+            let network_bits = net.network().to_bits();
+            let rand_host_bits = rng.random_range(0..(1u128 << host_len));
+
+            IpAddr::V6(Ipv6Addr::from_bits(network_bits | rand_host_bits))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn random_subnet_is_inside_cidr() {
+        for cidr in [
+            "10.0.0.0/8",
+            "192.0.2.0/24",
+            "2001:db8::/32",
+            "2001:db8::/48",
+        ] {
+            let cidr: IpNet = cidr.parse().unwrap();
+            for prefix_len in cidr.prefix_len()..=cidr.max_prefix_len() {
+                let subnet = random_subnet(cidr, prefix_len);
+                assert_eq!(subnet.prefix_len(), prefix_len);
+                assert_eq!(subnet, subnet.trunc(), "{} has host bits set", subnet);
+                assert!(cidr.contains(&subnet), "{} is not in {}", subnet, cidr);
+            }
+        }
+    }
+
+    #[test]
+    fn random_subnet_clamps_prefix_len() {
+        let cidr: IpNet = "192.0.2.77/24".parse().unwrap();
+        assert_eq!(random_subnet(cidr, 16), cidr.trunc());
+        assert_eq!(random_subnet(cidr, 24), cidr.trunc());
+        assert_eq!(random_subnet(cidr, 64).prefix_len(), 32);
+
+        let cidr: IpNet = "2001:db8::/64".parse().unwrap();
+        assert_eq!(random_subnet(cidr, 200).prefix_len(), 128);
+    }
+
+    #[test]
+    fn random_subnet_handles_full_width() {
+        let cidr: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert_eq!(random_subnet(cidr, 32).prefix_len(), 32);
+
+        let cidr: IpNet = "::/0".parse().unwrap();
+        assert_eq!(random_subnet(cidr, 128).prefix_len(), 128);
+    }
+
+    #[test]
+    fn random_subnet_covers_all_subnets() {
+        let cidr: IpNet = "192.0.2.0/24".parse().unwrap();
+        let subnets: HashSet<_> = (0..1000).map(|_| random_subnet(cidr, 26)).collect();
+        assert_eq!(subnets.len(), 4);
+
+        let cidr: IpNet = "2001:db8::/62".parse().unwrap();
+        let subnets: HashSet<_> = (0..1000).map(|_| random_subnet(cidr, 64)).collect();
+        assert_eq!(subnets.len(), 4);
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use http::uri::{Scheme, Uri};
+use tokio::time::Instant;
+
+use crate::config::CircuitBreakerConfig;
+
+/// Number of entries the table may hold before stale circuits are pruned.
+const MIN_PRUNE_LEN: usize = 1024;
+
+/// Circuit breakers of destinations, keyed by `host:port`.
+///
+/// A circuit opens after `failures` consecutive failed connects to its destination and fails
+/// requests fast for `cooldown`. After that it is half-open, a single request is let through to
+/// probe the destination and the others keep failing until the probe reports back or another
+/// `cooldown` has passed.
+#[derive(Clone, Debug, Default)]
+pub struct Breakers {
+    inner: Arc<Mutex<BreakersInner>>,
+}
+
+#[derive(Debug, Default)]
+struct BreakersInner {
+    circuits: HashMap<Box<str>, Circuit>,
+    prune_len: usize,
+}
+
+#[derive(Debug)]
+struct Circuit {
+    failures: u32,
+    last_failure: Instant,
+    open_until: Option<Instant>,
+}
+
+impl Breakers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns whether a request to `dst` may be made.
+    pub fn try_acquire(&self, dst: &str, config: &CircuitBreakerConfig) -> bool {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+
+        let Some(until) = inner
+            .circuits
+            .get_mut(dst)
+            .and_then(|circuit| circuit.open_until.as_mut())
+        else {
+            return true;
+        };
+
+        if now < *until {
+            return false;
+        }
+
+        tracing::debug!("circuit for {} is half-open, probing", dst);
+        *until = now + config.cooldown;
+        true
+    }
+
+    /// Records the outcome of a connect to `dst`.
+    pub fn record(&self, dst: &str, connected: bool, config: &CircuitBreakerConfig) {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+
+        if connected {
+            if let Some(circuit) = inner.circuits.remove(dst) {
+                if circuit.open_until.is_some() {
+                    tracing::debug!("circuit for {} is closed", dst);
+                }
+            }
+            return;
+        }
+
+        if inner.circuits.len() >= inner.prune_len.max(MIN_PRUNE_LEN) {
+            inner.circuits.retain(|_, circuit| {
+                now.duration_since(circuit.last_failure) < config.cooldown
+                    || circuit.open_until.is_some_and(|until| until > now)
+            });
+            inner.prune_len = inner.circuits.len() * 2;
+        }
+
+        let circuit = inner.circuits.entry(dst.into()).or_insert(Circuit {
+            failures: 0,
+            last_failure: now,
+            open_until: None,
+        });
+
+        circuit.failures = circuit.failures.saturating_add(1);
+        circuit.last_failure = now;
+
+        if circuit.open_until.is_some() || circuit.failures >= config.failures {
+            if circuit.open_until.is_none() {
+                tracing::debug!(
+                    "circuit for {} is open after {} failures",
+                    dst,
+                    circuit.failures
+                );
+            }
+            circuit.open_until = Some(now + config.cooldown);
+        }
+    }
+}
+
+/// Returns the `host:port` key of the destination of `uri`.
+pub fn destination(uri: &Uri) -> Option<String> {
+    let host = uri.host()?;
+    let port = match uri.port_u16() {
+        Some(port) => port,
+        None if uri.scheme() == Some(&Scheme::HTTPS) => 443,
+        None => 80,
+    };
+
+    Some(format!("{}:{}", host.to_ascii_lowercase(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const DST: &str = "example.com:80";
+    const COOLDOWN: Duration = Duration::from_secs(30);
+
+    fn config() -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            failures: 2,
+            cooldown: COOLDOWN,
+        }
+    }
+
+    /// Returns breakers with the circuit of [`DST`] just opened.
+    fn open() -> Breakers {
+        let breakers = Breakers::new();
+        let config = config();
+        breakers.record(DST, false, &config);
+        assert!(breakers.try_acquire(DST, &config));
+        breakers.record(DST, false, &config);
+        assert!(!breakers.try_acquire(DST, &config));
+        breakers
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn opens_after_consecutive_failures() {
+        let breakers = Breakers::new();
+        let config = config();
+
+        breakers.record(DST, false, &config);
+        breakers.record(DST, true, &config);
+        breakers.record(DST, false, &config);
+        assert!(breakers.try_acquire(DST, &config));
+
+        breakers.record(DST, false, &config);
+        assert!(!breakers.try_acquire(DST, &config));
+        assert!(breakers.try_acquire("example.org:80", &config));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn half_open_lets_a_single_probe_through() {
+        let breakers = open();
+        let config = config();
+
+        tokio::time::advance(COOLDOWN - Duration::from_secs(1)).await;
+        assert!(!breakers.try_acquire(DST, &config));
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(breakers.try_acquire(DST, &config));
+        assert!(!breakers.try_acquire(DST, &config));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn successful_probe_closes_the_circuit() {
+        let breakers = open();
+        let config = config();
+
+        tokio::time::advance(COOLDOWN).await;
+        assert!(breakers.try_acquire(DST, &config));
+        breakers.record(DST, true, &config);
+
+        assert!(breakers.try_acquire(DST, &config));
+        assert!(breakers.try_acquire(DST, &config));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_probe_reopens_the_circuit() {
+        let breakers = open();
+        let config = config();
+
+        tokio::time::advance(COOLDOWN).await;
+        assert!(breakers.try_acquire(DST, &config));
+        breakers.record(DST, false, &config);
+
+        tokio::time::advance(COOLDOWN - Duration::from_secs(1)).await;
+        assert!(!breakers.try_acquire(DST, &config));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(breakers.try_acquire(DST, &config));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lost_probe_is_retried_after_another_cooldown() {
+        let breakers = open();
+        let config = config();
+
+        tokio::time::advance(COOLDOWN).await;
+        assert!(breakers.try_acquire(DST, &config));
+
+        // the probe never reports back
+        tokio::time::advance(COOLDOWN).await;
+        assert!(breakers.try_acquire(DST, &config));
+        assert!(!breakers.try_acquire(DST, &config));
+    }
+
+    #[test]
+    fn destination_defaults_the_port() {
+        let uri = |s: &str| s.parse::<Uri>().unwrap();
+        assert_eq!(
+            destination(&uri("http://Example.com/")).as_deref(),
+            Some("example.com:80")
+        );
+        assert_eq!(
+            destination(&uri("https://example.com/")).as_deref(),
+            Some("example.com:443")
+        );
+        assert_eq!(
+            destination(&uri("http://example.com:8080/")).as_deref(),
+            Some("example.com:8080")
+        );
+    }
+}
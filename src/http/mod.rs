@@ -1,4 +1,7 @@
+mod breaker;
 mod error;
+mod pool;
 mod proxy;
+mod tunnel;
 
 pub use proxy::HttpProxy;
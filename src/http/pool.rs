@@ -0,0 +1,135 @@
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioTimer};
+use ipnet::IpNet;
+use lru::LruCache;
+
+use crate::config::{Config, ConnectorConfig, DnsConfig, PoolConfig, RateLimitConfig};
+use crate::connect::dns::{BoxResolver, IpPreference};
+use crate::connect::tcp::{RetryPolicy, TcpConnector};
+
+pub type HttpClient = Client<TcpConnector<BoxResolver>, BoxBody<Bytes, hyper::Error>>;
+
+/// Most clients kept, the least recently used are dropped.
+const MAX_CLIENTS: NonZeroUsize = NonZeroUsize::new(1024).unwrap();
+
+/// Where the connections of a client are made from.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Key {
+    /// The default local address.
+    Direct,
+    /// A single egress address, leased to a session or picked for one request.
+    Addr(IpAddr),
+    /// Addresses of an egress pool, picked for each new connection.
+    ///
+    /// Idle connections are reused whatever address of the pool they were made from.
+    Pool(IpNet),
+}
+
+/// Long-lived clients for plain HTTP forwarding, one per egress policy, so upstream
+/// connections are pooled across requests.
+///
+/// All clients are dropped when a setting they were built with changes.
+#[derive(Clone)]
+pub struct Clients {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    /// The config generation the settings were last checked in.
+    generation: u64,
+    settings: Option<Settings>,
+    clients: LruCache<Key, HttpClient>,
+}
+
+/// The settings clients and their connectors are built with.
+#[derive(PartialEq)]
+struct Settings {
+    pool: PoolConfig,
+    connect_timeout: Option<Duration>,
+    connector: ConnectorConfig,
+    retry: RetryPolicy,
+    circuit_breaker: bool,
+    cidr: Option<IpNet>,
+    source_port_range: Option<RangeInclusive<u16>>,
+    ip_preference: IpPreference,
+    rate_limit: Option<RateLimitConfig>,
+    dns: DnsConfig,
+}
+
+impl Settings {
+    fn new(config: &Config) -> Self {
+        Self {
+            pool: config.pool.clone(),
+            connect_timeout: config.connect_timeout,
+            connector: config.connector.clone(),
+            retry: config.retry.clone(),
+            circuit_breaker: config.circuit_breaker.is_some(),
+            cidr: config.cidr,
+            source_port_range: config.source_port_range.clone(),
+            ip_preference: config.ip_preference,
+            rate_limit: config.rate_limit.clone(),
+            dns: config.dns.clone(),
+        }
+    }
+}
+
+impl Clients {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                generation: 0,
+                settings: None,
+                clients: LruCache::new(MAX_CLIENTS),
+            })),
+        }
+    }
+
+    /// Returns the client for `key`, building it with `connector` if there is none yet.
+    pub fn get(
+        &self,
+        config: &Config,
+        key: Key,
+        connector: impl FnOnce() -> TcpConnector<BoxResolver>,
+    ) -> HttpClient {
+        let mut inner = self.inner.lock().unwrap();
+        // the settings can only have changed when the config was reloaded
+        if inner.settings.is_none() || inner.generation != config.generation {
+            let settings = Settings::new(config);
+            if inner.settings.as_ref() != Some(&settings) {
+                if inner.settings.is_some() {
+                    tracing::debug!("connection pool settings changed, dropping pooled clients");
+                }
+                inner.clients.clear();
+                inner.settings = Some(settings);
+            }
+            inner.generation = config.generation;
+        }
+
+        inner
+            .clients
+            .get_or_insert(key, || {
+                Client::builder(TokioExecutor::new())
+                    .pool_timer(TokioTimer::new())
+                    .pool_idle_timeout(config.pool.idle_timeout)
+                    .pool_max_idle_per_host(config.pool.max_idle_per_host)
+                    .http1_preserve_header_case(true)
+                    .http1_title_case_headers(true)
+                    .build(connector())
+            })
+            .clone()
+    }
+}
+
+impl std::fmt::Debug for Clients {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad("Clients")
+    }
+}
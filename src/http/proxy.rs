@@ -1,24 +1,71 @@
+use std::error::Error as StdError;
 use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
 use std::sync::{Arc, RwLock};
 use std::task::{Context, Poll};
 
 use bytes::Bytes;
+use http::request::Parts;
 use http::{Method, StatusCode, Uri};
 use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
-use hyper::{body::Incoming, upgrade::Upgraded, Request, Response};
-use hyper_util::client::legacy::Client;
-use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper::body::{Body, Incoming};
+use hyper::{upgrade::Upgraded, Request, Response};
+use hyper_util::client::legacy::connect::{capture_connection, CaptureConnection, HttpInfo};
+use hyper_util::rt::TokioIo;
+use ipnet::IpNet;
 use tokio::net::TcpStream;
+use tokio::sync::OwnedSemaphorePermit;
 use tower_service::Service;
 
+use super::breaker::{self, Breakers};
 use super::error::Error;
-use crate::{config::Config, connect::tcp::TcpConnector};
+use super::pool::{Clients, Key};
+use super::tunnel::{self, CloseReason};
+use crate::bandwidth::{Bandwidth, Direction, Throttle, Throttled};
+use crate::config::{Config, TunnelConfig};
+use crate::connect::dns::BoxResolver;
+use crate::connect::tcp::{LocalAddressPicker, RetryOn, RetryPolicy, TcpConnector};
+use crate::egress::{self, Blocks, Budgets, Egress, EgressError, Leases, Session};
+use crate::limit::Limit;
+use crate::resolver::Resolvers;
 
 #[derive(Debug, Clone)]
 pub struct HttpProxy {
-    #[allow(dead_code)]
     config: Arc<RwLock<Config>>,
+    session: Arc<Session>,
+    leases: Leases,
+    budgets: Budgets,
+    blocks: Blocks,
+    resolvers: Resolvers,
+    clients: Clients,
+    bandwidth: Bandwidth,
+    breakers: Breakers,
+    outbound: Limit,
+    /// The client connection permit, held until the connection and its tunnels are closed.
+    _permit: Option<Arc<OwnedSemaphorePermit>>,
+}
+
+/// Largest request body that is buffered so the request can be retried.
+const MAX_REPLAY_BODY: u64 = 64 * 1024;
+
+/// Where the upstream connections of a plain HTTP request are made from.
+enum Source {
+    /// The address of the egress, or the default one without `cidr`.
+    Egress(Option<Egress>),
+    /// Addresses of an egress pool, picked for each new connection.
+    Pool(IpNet),
+}
+
+impl Source {
+    fn key(&self) -> Key {
+        match self {
+            Source::Egress(Some(egress)) => Key::Addr(egress.addr()),
+            Source::Egress(None) => Key::Direct,
+            Source::Pool(pool) => Key::Pool(*pool),
+        }
+    }
 }
 
 impl Service<Request<Incoming>> for HttpProxy {
@@ -45,8 +92,187 @@ impl Service<Request<Incoming>> for HttpProxy {
 }
 
 impl HttpProxy {
-    pub fn new(config: Arc<RwLock<Config>>) -> Self {
-        Self { config }
+    pub fn new(config: Arc<RwLock<Config>>, resolvers: Resolvers) -> Self {
+        Self {
+            config,
+            session: Arc::new(Session::default()),
+            leases: Leases::new(),
+            budgets: Budgets::new(),
+            blocks: Blocks::new(),
+            resolvers,
+            clients: Clients::new(),
+            bandwidth: Bandwidth::new(),
+            breakers: Breakers::new(),
+            outbound: Limit::new("upstream connections"),
+            _permit: None,
+        }
+    }
+
+    /// Returns a proxy bound to a new session for the client at `addr`, connected to the local
+    /// address `listener` and holding on to the connection `permit`.
+    pub fn with_client(
+        &self,
+        addr: SocketAddr,
+        listener: Option<SocketAddr>,
+        permit: OwnedSemaphorePermit,
+    ) -> Self {
+        Self {
+            config: self.config.clone(),
+            session: Arc::new(Session::new(addr)),
+            leases: self.leases.clone(),
+            budgets: self.budgets.clone(),
+            blocks: self.blocks.clone(),
+            resolvers: self.resolvers.clone(),
+            clients: self.clients.clone(),
+            bandwidth: self.bandwidth.for_connection(listener),
+            breakers: self.breakers.clone(),
+            outbound: self.outbound.clone(),
+            _permit: Some(Arc::new(permit)),
+        }
+    }
+
+    async fn egress(&self, config: &Config, uri: &Uri) -> Result<Option<Egress>, EgressError> {
+        let Some(cidr) = config.cidr else {
+            return Ok(None);
+        };
+
+        let mut accept = |addr| {
+            let Some(host) = uri.host() else {
+                return true;
+            };
+
+            !self.blocks.is_blocked(addr, host)
+                && config
+                    .rate_limit
+                    .as_ref()
+                    .is_none_or(|rate_limit| self.budgets.has_budget(addr, host, rate_limit))
+        };
+
+        loop {
+            let egress = self
+                .session
+                .egress(cidr, config, &self.leases, &mut accept)
+                .await?;
+
+            // another request may have spent the rest of the budget since it was checked
+            if let (Some(rate_limit), Some(host)) = (&config.rate_limit, uri.host()) {
+                if !self.budgets.try_take(egress.addr(), host, rate_limit) {
+                    continue;
+                }
+            }
+
+            tracing::trace!("assigning local address: {:?}", egress.addr());
+            return Ok(Some(egress));
+        }
+    }
+
+    /// Returns where the upstream connections of a plain HTTP request to `uri` are made from.
+    ///
+    /// Without leases no address belongs to a session, so each new connection picks one from
+    /// the pool of the session and idle connections are shared by all sessions of that pool.
+    async fn source(&self, config: &Config, uri: &Uri) -> Result<Source, EgressError> {
+        match (config.cidr, &config.lease) {
+            (Some(cidr), None) => Ok(Source::Pool(
+                self.session.pool(cidr, config.cidr_subnet_len),
+            )),
+            _ => self.egress(config, uri).await.map(Source::Egress),
+        }
+    }
+
+    /// Returns a picker of addresses of `pool` that are neither blocked by the destination nor
+    /// out of budget for it.
+    ///
+    /// Budgets are only checked here, requests on pooled connections are paid for once they
+    /// were sent.
+    fn picker(&self, config: &Config, pool: IpNet) -> LocalAddressPicker {
+        let blocks = self.blocks.clone();
+        let budgets = self.budgets.clone();
+        let rate_limit = config.rate_limit.clone();
+
+        Arc::new(move |host| {
+            egress::pick(pool, |addr| {
+                !blocks.is_blocked(addr, host)
+                    && rate_limit
+                        .as_ref()
+                        .is_none_or(|rate_limit| budgets.has_budget(addr, host, rate_limit))
+            })
+            .ok_or_else(|| io::Error::other(EgressError::Unavailable(pool)))
+        })
+    }
+
+    fn connector(&self, config: &Config, egress: Option<&Egress>) -> TcpConnector<BoxResolver> {
+        let mut connector = TcpConnector::new_with_resolver(self.resolvers.get(config));
+        connector.set_connect_timeout(config.connect_timeout);
+        connector.set_connect_timeout_mode(config.connector.connect_timeout_mode);
+        connector.set_connect_strategy(config.connector.connect_strategy);
+        connector.set_stagger_delay(config.connector.stagger_delay);
+        connector.set_happy_eyeballs_timeout(config.connector.happy_eyeballs_timeout);
+        connector.set_nodelay(config.connector.nodelay);
+        connector.set_keepalive(config.connector.keepalive.clone());
+        connector.set_user_timeout(config.connector.user_timeout);
+        // with TFO a refused connect looks successful, which retries and breakers rely on
+        connector.set_fast_open(config.fast_open());
+        connector.set_retry(config.retry.clone());
+        connector.set_send_buffer_size(config.connector.send_buffer_size);
+        connector.set_recv_buffer_size(config.connector.recv_buffer_size);
+        connector.set_local_address(egress.map(Egress::addr));
+        connector.set_source_port_range(config.source_port_range.clone());
+        connector.set_ip_preference(config.ip_preference);
+        connector.set_nat64_prefix(config.dns.dns64.as_ref().map(|dns64| dns64.prefix));
+
+        connector
+    }
+
+    /// Returns a 503 response if the circuit of the destination of `uri` is open.
+    fn open_circuit(
+        &self,
+        config: &Config,
+        uri: &Uri,
+    ) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+        let breaker = config.circuit_breaker.as_ref()?;
+        let dst = breaker::destination(uri)?;
+        if self.breakers.try_acquire(&dst, breaker) {
+            return None;
+        }
+
+        tracing::debug!("circuit for {} is open, failing fast", dst);
+        let mut resp = Response::new(full(format!("circuit for {dst} is open")));
+        *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+
+        Some(resp)
+    }
+
+    fn record_connect(&self, config: &Config, uri: &Uri, connected: bool) {
+        if let (Some(breaker), Some(dst)) = (&config.circuit_breaker, breaker::destination(uri)) {
+            self.breakers.record(&dst, connected, breaker);
+        }
+    }
+
+    /// Takes a permit for an upstream connection, `Ok(None)` when they are not limited.
+    async fn outbound(
+        &self,
+        config: &Config,
+    ) -> Result<Option<OwnedSemaphorePermit>, Response<BoxBody<Bytes, hyper::Error>>> {
+        let Some(concurrent) = config.concurrent_outbound else {
+            return Ok(None);
+        };
+
+        match self.outbound.acquire(concurrent, &config.overload).await {
+            Some(permit) => Ok(Some(permit)),
+            None => {
+                let mut resp = Response::new(full("too many upstream connections"));
+                *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                Err(resp)
+            }
+        }
+    }
+
+    fn throttle(&self, egress: Option<&Egress>) -> Throttle {
+        self.bandwidth.throttle(
+            self.config.clone(),
+            self.session.client().map(|addr| addr.ip()),
+            egress.map(Egress::addr),
+        )
     }
 
     async fn http(
@@ -55,24 +281,179 @@ impl HttpProxy {
     ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
         // Handles regular HTTP connections by forwarding the request to the destination
 
-        let Config {
-            connect_timeout,
-            cidr,
-            ..
-        } = self.config.read().unwrap().clone();
+        let config = self.config.read().unwrap().clone();
+
+        let block_retry = config
+            .block_retry
+            .as_ref()
+            .filter(|_| config.cidr.is_some());
+        let (parts, body) = req.into_parts();
+
+        if let Some(resp) = self.open_circuit(&config, &parts.uri) {
+            return Ok(resp);
+        }
+        let permit = match self.outbound(&config).await {
+            Ok(permit) => permit,
+            Err(resp) => return Ok(resp),
+        };
+
+        let wants_replay = block_retry.is_some_and(|block_retry| block_retry.attempts > 1)
+            || config.retry.attempts > 1;
+
+        let uri = parts.uri.clone();
+
+        // only idempotent requests with a small body can be replayed
+        let (mut request, replay) = if wants_replay
+            && parts.method.is_idempotent()
+            && body
+                .size_hint()
+                .exact()
+                .is_some_and(|len| len <= MAX_REPLAY_BODY)
+        {
+            (None, Some((parts, body.collect().await?.to_bytes())))
+        } else {
+            (Some((parts, body.boxed())), None)
+        };
+
+        let (attempts, retries) = if replay.is_some() {
+            (
+                block_retry.map_or(1, |block_retry| block_retry.attempts),
+                config.retry.attempts.saturating_sub(1),
+            )
+        } else {
+            (1, 0)
+        };
+
+        let mut attempt = 1;
+        let mut retry = 0;
+        let mut last_blocked = None;
+        loop {
+            let source = match self.source(&config, &uri).await {
+                Ok(source) => source,
+                Err(e) => return Ok(last_blocked.unwrap_or_else(|| unavailable(&e))),
+            };
+            let egress = match &source {
+                Source::Egress(egress) => egress.as_ref(),
+                Source::Pool(_) => None,
+            };
+            let (parts, body) = match (&replay, request.take()) {
+                (Some((parts, bytes)), _) => (head(parts), full(bytes.clone())),
+                (None, Some(request)) => request,
+                (None, None) => unreachable!("request already sent"),
+            };
+            let throttle = self.throttle(egress);
+
+            let mut req = Request::from_parts(parts, body);
+            let captured = capture_connection(&mut req);
+            let req = req.map(|body| {
+                let body = match &source {
+                    // the address of a pooled connection is known once the request is sent
+                    Source::Pool(_) => {
+                        let captured = captured.clone();
+                        let throttle = throttle.clone();
+                        body.map_frame(move |frame| {
+                            if let Some(addr) = local_addr(&captured) {
+                                throttle.set_egress(addr);
+                            }
+                            frame
+                        })
+                        .boxed()
+                    }
+                    Source::Egress(_) => body,
+                };
+                Throttled::new(body, throttle.clone(), Direction::Upload).boxed()
+            });
+
+            let result = self
+                .clients
+                .get(&config, source.key(), || {
+                    let mut connector = self.connector(&config, egress);
+                    if let Source::Pool(pool) = source {
+                        connector.set_local_address_picker(Some(self.picker(&config, pool)));
+                    }
+                    connector
+                })
+                .request(req)
+                .await;
+            if let Some(e) = result.as_ref().err().and_then(egress_error) {
+                return Ok(last_blocked.unwrap_or_else(|| unavailable(e)));
+            }
+            self.record_connect(&config, &uri, !matches!(&result, Err(e) if e.is_connect()));
+
+            let addr = match &source {
+                Source::Egress(egress) => egress.as_ref().map(Egress::addr),
+                Source::Pool(_) => local_addr(&captured),
+            };
+            if let (Source::Pool(_), Some(rate_limit), Some(addr), Some(host)) =
+                (&source, &config.rate_limit, addr, uri.host())
+            {
+                // a connection is not reused once its address spent the budget
+                self.budgets.try_take(addr, host, rate_limit);
+                if !self.budgets.has_budget(addr, host, rate_limit) {
+                    poison(&captured);
+                }
+            }
 
-        let mut connector = TcpConnector::new();
-        connector.set_connect_timeout(connect_timeout);
-        connector.assign_local_address_from_cidr(cidr);
+            let is_blocked = match &result {
+                Ok(resp) => block_retry.is_some_and(|block_retry| {
+                    block_retry.statuses.contains(&resp.status().as_u16())
+                }),
+                Err(e) => block_retry.is_some() && is_connection_reset(e),
+            };
 
-        let resp = Client::builder(TokioExecutor::new())
-            .http1_preserve_header_case(true)
-            .http1_title_case_headers(true)
-            .build(connector)
-            .request(req)
-            .await?;
+            if let (true, Some(block_retry), Some(addr), Some(host)) =
+                (is_blocked, block_retry, addr, uri.host())
+            {
+                tracing::debug!(
+                    "egress address {} blocked by {} (attempt {}/{})",
+                    addr,
+                    host,
+                    attempt,
+                    attempts
+                );
+                self.blocks.block(addr, host, block_retry.cooldown);
+                poison(&captured);
 
-        Ok(resp.map(|b| b.boxed()))
+                if attempt < attempts {
+                    if let Ok(resp) = result {
+                        last_blocked = Some(resp.map(|b| b.boxed()));
+                    }
+                    attempt += 1;
+                    continue;
+                }
+            }
+
+            let resp = match result {
+                Err(e) if !is_blocked && retry < retries && is_retriable(&e, &config.retry) => {
+                    retry += 1;
+                    let backoff = config.retry.backoff(retry);
+                    tracing::debug!(
+                        "request to {} failed: {}, retrying in {:?}",
+                        uri,
+                        e,
+                        backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    continue;
+                }
+                result => result?,
+            };
+
+            if let Some(addr) = addr {
+                throttle.set_egress(addr);
+            }
+
+            // keep the egress address and the upstream permit until the response body is done
+            // with the connection
+            return Ok(resp.map(|b| {
+                Throttled::new(b, throttle, Direction::Download)
+                    .map_frame(move |frame| {
+                        let _ = (&source, &permit);
+                        frame
+                    })
+                    .boxed()
+            }));
+        }
     }
 
     async fn connect(
@@ -89,12 +470,32 @@ impl HttpProxy {
             return Ok(resp);
         }
 
+        let config = self.config.read().unwrap().clone();
+        if let Some(resp) = self.open_circuit(&config, &uri) {
+            return Ok(resp);
+        }
+        let permit = match self.outbound(&config).await {
+            Ok(permit) => permit,
+            Err(resp) => return Ok(resp),
+        };
+
+        let egress = match self.egress(&config, &uri).await {
+            Ok(egress) => egress,
+            Err(e) => return Ok(unavailable(&e)),
+        };
+
         tokio::task::spawn(async move {
             match hyper::upgrade::on(req).await {
                 Ok(upgraded) => {
-                    if let Err(e) = self.establish_tunnel(upgraded, uri).await {
+                    let connector = self.connector(&config, egress.as_ref());
+                    let throttle = self.throttle(egress.as_ref());
+                    if let Err(e) = self
+                        .establish_tunnel(connector, upgraded, uri, &throttle, &config)
+                        .await
+                    {
                         tracing::warn!("tunnel error: {}", e);
                     }
+                    drop(permit);
                 }
                 Err(e) => tracing::warn!("upgrade error: {}", e),
             }
@@ -104,42 +505,150 @@ impl HttpProxy {
         Ok(Response::new(empty()))
     }
 
-    async fn establish_tunnel(&self, upgraded: Upgraded, uri: Uri) -> Result<(), Error> {
-        let Config {
-            connect_timeout,
-            cidr,
-            ..
-        } = self.config.read().unwrap().clone();
-
-        let mut connector = TcpConnector::new();
-        connector.set_connect_timeout(connect_timeout);
-        connector.assign_local_address_from_cidr(cidr);
-
+    async fn establish_tunnel(
+        &self,
+        mut connector: TcpConnector<BoxResolver>,
+        upgraded: Upgraded,
+        uri: Uri,
+        throttle: &Throttle,
+        config: &Config,
+    ) -> Result<(), Error> {
         futures_util::future::poll_fn(|cx| connector.poll_ready(cx)).await?;
 
-        let server = connector.call(uri).await?.into_inner();
+        let server = connector.call(uri.clone()).await;
+        self.record_connect(config, &uri, server.is_ok());
+        let server = server?.into_inner();
 
-        self.tunnel(TokioIo::new(upgraded), server).await
+        self.tunnel(upgraded, server, &uri, throttle, &config.tunnel)
+            .await
     }
 
     async fn tunnel(
         &self,
-        mut upgraded: TokioIo<Upgraded>,
-        mut server: TcpStream,
+        upgraded: Upgraded,
+        server: TcpStream,
+        uri: &Uri,
+        throttle: &Throttle,
+        config: &TunnelConfig,
     ) -> Result<(), Error> {
-        let (from_client, from_server) =
-            tokio::io::copy_bidirectional(&mut upgraded, &mut server).await?;
+        // splice needs the client's TcpStream, which plain HTTP/1 connections give back
+        #[cfg(target_os = "linux")]
+        let upgraded = if config.splice {
+            match upgraded.downcast::<TokioIo<TcpStream>>() {
+                Ok(parts) => {
+                    tracing::trace!("relaying tunnel to {} with splice", uri);
+                    let client = parts.io.into_inner();
+                    let closed =
+                        tunnel::splice(client, server, &parts.read_buf, throttle, config).await;
+                    return tunnel_closed(uri, closed);
+                }
+                Err(upgraded) => {
+                    tracing::trace!("cannot splice tunnel to {}, copying instead", uri);
+                    upgraded
+                }
+            }
+        } else {
+            upgraded
+        };
+
+        let closed = tunnel::relay(TokioIo::new(upgraded), server, throttle, config).await;
+        tunnel_closed(uri, closed)
+    }
+}
+
+fn tunnel_closed(uri: &Uri, closed: tunnel::Closed) -> Result<(), Error> {
+    tracing::debug!(
+        "tunnel to {} closed ({}), client wrote {} bytes and received {} bytes",
+        uri,
+        closed.reason,
+        closed.from_client,
+        closed.from_server
+    );
 
-        tracing::trace!(
-            "client wrote {} bytes and received {} bytes",
-            from_client,
-            from_server
-        );
+    match closed.reason {
+        CloseReason::Error(e) => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Copies the head of a request for a replay, without its extensions, which cannot be cloned.
+fn head(parts: &Parts) -> Parts {
+    let mut req = Request::new(());
+    *req.method_mut() = parts.method.clone();
+    *req.uri_mut() = parts.uri.clone();
+    *req.version_mut() = parts.version;
+    *req.headers_mut() = parts.headers.clone();
+
+    req.into_parts().0
+}
+
+/// Returns whether the request failed, before any response was received, with an error
+/// `policy` retries.
+fn is_retriable(err: &hyper_util::client::legacy::Error, policy: &RetryPolicy) -> bool {
+    // failed connects were already retried by the connector
+    if err.is_connect() {
+        return false;
+    }
+
+    let mut source = err.source();
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            return policy.is_retriable(err);
+        }
+        if let Some(err) = err.downcast_ref::<hyper::Error>() {
+            if err.is_incomplete_message() {
+                return policy.on.contains(&RetryOn::Reset);
+            }
+        }
+        source = err.source();
+    }
+
+    false
+}
+
+/// Returns the error of a request that found no egress address to connect from.
+fn egress_error(err: &hyper_util::client::legacy::Error) -> Option<&EgressError> {
+    let mut source = err.source();
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            return err.get_ref()?.downcast_ref();
+        }
+        source = err.source();
+    }
+
+    None
+}
 
-        Ok(())
+/// Returns the local address of the connection a request was sent on.
+fn local_addr(captured: &CaptureConnection) -> Option<IpAddr> {
+    let connected = captured.connection_metadata();
+    let mut extensions = http::Extensions::new();
+    connected.as_ref()?.get_extras(&mut extensions);
+
+    extensions
+        .get::<HttpInfo>()
+        .map(|info| info.local_addr().ip())
+}
+
+/// Keeps the connection a request was sent on from being reused.
+fn poison(captured: &CaptureConnection) {
+    if let Some(connected) = captured.connection_metadata().as_ref() {
+        connected.poison();
     }
 }
 
+fn is_connection_reset(err: &hyper_util::client::legacy::Error) -> bool {
+    let mut source = err.source();
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            return err.kind() == io::ErrorKind::ConnectionReset;
+        }
+        source = err.source();
+    }
+
+    false
+}
+
 fn host_addr(uri: &Uri) -> Option<String> {
     uri.authority().map(|auth| auth.to_string())
 }
@@ -150,6 +659,14 @@ fn empty() -> BoxBody<Bytes, hyper::Error> {
         .boxed()
 }
 
+fn unavailable(err: &EgressError) -> Response<BoxBody<Bytes, hyper::Error>> {
+    tracing::warn!("no egress address: {}", err);
+    let mut resp = Response::new(full(err.to_string()));
+    *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+
+    resp
+}
+
 fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, hyper::Error> {
     Full::new(chunk.into())
         .map_err(|never| match never {})
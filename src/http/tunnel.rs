@@ -0,0 +1,376 @@
+use std::fmt;
+use std::future::Future;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use tokio::io::Interest;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+#[cfg(target_os = "linux")]
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+use crate::bandwidth::{Direction, Throttle};
+use crate::config::TunnelConfig;
+
+const BUF_SIZE: usize = 8 * 1024;
+
+/// Why a tunnel was closed.
+#[derive(Debug)]
+pub enum CloseReason {
+    /// Both sides closed their half of the tunnel.
+    Closed,
+    /// No bytes were relayed in either direction for `idle_timeout`.
+    Idle,
+    /// The tunnel was open for `max_lifetime`.
+    Lifetime,
+    /// One side closed its half and the other did not follow within `half_close_timeout`.
+    HalfClose,
+    /// Relaying failed.
+    Error(io::Error),
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::Closed => f.write_str("closed"),
+            CloseReason::Idle => f.write_str("idle timeout"),
+            CloseReason::Lifetime => f.write_str("maximum lifetime reached"),
+            CloseReason::HalfClose => f.write_str("half-close timeout"),
+            CloseReason::Error(e) => write!(f, "error: {e}"),
+        }
+    }
+}
+
+/// Outcome of a relayed tunnel.
+#[derive(Debug)]
+pub struct Closed {
+    pub reason: CloseReason,
+    pub from_client: u64,
+    pub from_server: u64,
+}
+
+/// Bytes relayed by a tunnel and when they were last relayed.
+struct Activity {
+    start: Instant,
+    /// Milliseconds since `start`.
+    last: AtomicU64,
+    from_client: AtomicU64,
+    from_server: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            from_client: AtomicU64::new(0),
+            from_server: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    fn count(&self, direction: Direction, n: usize) {
+        let count = match direction {
+            Direction::Upload => &self.from_client,
+            Direction::Download => &self.from_server,
+        };
+        count.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.start + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+}
+
+/// Relays bytes between `client` and `server` until both sides are closed or one of the
+/// timeouts of `config` expires.
+pub async fn relay<C, S>(client: C, server: S, throttle: &Throttle, config: &TunnelConfig) -> Closed
+where
+    C: AsyncRead + AsyncWrite,
+    S: AsyncRead + AsyncWrite,
+{
+    let activity = Activity::new();
+
+    let (client_read, client_write) = tokio::io::split(client);
+    let (server_read, server_write) = tokio::io::split(server);
+
+    let upstream = copy(
+        client_read,
+        server_write,
+        &activity,
+        throttle,
+        Direction::Upload,
+    );
+    let downstream = copy(
+        server_read,
+        client_write,
+        &activity,
+        throttle,
+        Direction::Download,
+    );
+
+    supervise(upstream, downstream, &activity, config).await
+}
+
+/// Like [`relay`], but moves the bytes with `splice(2)` through a pipe, so they never pass
+/// through userspace.
+///
+/// `read_buf` holds bytes the client sent before the tunnel was established, they are
+/// forwarded to `server` first.
+#[cfg(target_os = "linux")]
+pub async fn splice(
+    client: TcpStream,
+    mut server: TcpStream,
+    read_buf: &[u8],
+    throttle: &Throttle,
+    config: &TunnelConfig,
+) -> Closed {
+    let activity = Activity::new();
+
+    if !read_buf.is_empty() {
+        if let Err(e) = server.write_all(read_buf).await {
+            return Closed {
+                reason: CloseReason::Error(e),
+                from_client: 0,
+                from_server: 0,
+            };
+        }
+        activity.touch();
+        activity.count(Direction::Upload, read_buf.len());
+        throttle.consume(Direction::Upload, read_buf.len()).await;
+    }
+
+    let upstream = splice_copy(&client, &server, &activity, throttle, Direction::Upload);
+    let downstream = splice_copy(&server, &client, &activity, throttle, Direction::Download);
+
+    supervise(upstream, downstream, &activity, config).await
+}
+
+/// Drives both directions of a tunnel until they are done or one of the timeouts of `config`
+/// expires.
+async fn supervise(
+    upstream: impl Future<Output = io::Result<()>>,
+    downstream: impl Future<Output = io::Result<()>>,
+    activity: &Activity,
+    config: &TunnelConfig,
+) -> Closed {
+    tokio::pin!(upstream, downstream);
+
+    let mut upstream_done = false;
+    let mut downstream_done = false;
+    let mut half_closed = None;
+
+    let reason = loop {
+        if upstream_done && downstream_done {
+            break CloseReason::Closed;
+        }
+
+        let idle = config
+            .idle_timeout
+            .map(|t| (activity.last() + t, CloseReason::Idle));
+        let lifetime = config
+            .max_lifetime
+            .map(|t| (activity.start + t, CloseReason::Lifetime));
+        let half_close = half_closed
+            .zip(config.half_close_timeout)
+            .map(|(at, t)| (at + t, CloseReason::HalfClose));
+        let deadline = [idle, lifetime, half_close]
+            .into_iter()
+            .flatten()
+            .min_by_key(|(at, _)| *at);
+
+        tokio::select! {
+            result = &mut upstream, if !upstream_done => {
+                if let Err(e) = result {
+                    break CloseReason::Error(e);
+                }
+                upstream_done = true;
+                half_closed.get_or_insert_with(Instant::now);
+            }
+            result = &mut downstream, if !downstream_done => {
+                if let Err(e) = result {
+                    break CloseReason::Error(e);
+                }
+                downstream_done = true;
+                half_closed.get_or_insert_with(Instant::now);
+            }
+            reason = expire(deadline) => {
+                // bytes may have been relayed since the idle deadline was computed
+                let active = config
+                    .idle_timeout
+                    .is_some_and(|t| activity.last() + t > Instant::now());
+                if matches!(reason, CloseReason::Idle) && active {
+                    continue;
+                }
+                break reason;
+            }
+        }
+    };
+
+    Closed {
+        reason,
+        from_client: activity.from_client.load(Ordering::Relaxed),
+        from_server: activity.from_server.load(Ordering::Relaxed),
+    }
+}
+
+async fn expire(deadline: Option<(Instant, CloseReason)>) -> CloseReason {
+    match deadline {
+        Some((at, reason)) => {
+            tokio::time::sleep_until(at).await;
+            reason
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Copies `reader` to `writer` and shuts `writer` down at EOF.
+async fn copy<R, W>(
+    mut reader: R,
+    mut writer: W,
+    activity: &Activity,
+    throttle: &Throttle,
+    direction: Direction,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; BUF_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return writer.shutdown().await;
+        }
+        activity.touch();
+
+        writer.write_all(&buf[..n]).await?;
+        activity.touch();
+        activity.count(direction, n);
+
+        throttle.consume(direction, n).await;
+    }
+}
+
+/// Like [`copy`], but through a pipe with `splice(2)`.
+#[cfg(target_os = "linux")]
+async fn splice_copy(
+    reader: &TcpStream,
+    writer: &TcpStream,
+    activity: &Activity,
+    throttle: &Throttle,
+    direction: Direction,
+) -> io::Result<()> {
+    let pipe = sys::Pipe::new()?;
+    loop {
+        let n = loop {
+            reader.readable().await?;
+            match reader.try_io(Interest::READABLE, || {
+                sys::splice(reader.as_raw_fd(), pipe.write(), sys::PIPE_SIZE)
+            }) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        if n == 0 {
+            return sys::shutdown_write(writer);
+        }
+        activity.touch();
+
+        // the pipe is drained before reading again, so it never blocks on its own
+        let mut pending = n;
+        while pending > 0 {
+            writer.writable().await?;
+            match writer.try_io(Interest::WRITABLE, || {
+                sys::splice(pipe.read(), writer.as_raw_fd(), pending)
+            }) {
+                Ok(n) => pending -= n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        activity.touch();
+        activity.count(direction, n);
+
+        throttle.consume(direction, n).await;
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+    /// Bytes moved per `splice(2)` call, the default capacity of a pipe.
+    pub const PIPE_SIZE: usize = 64 * 1024;
+
+    pub struct Pipe {
+        read: OwnedFd,
+        write: OwnedFd,
+    }
+
+    impl Pipe {
+        pub fn new() -> io::Result<Self> {
+            let mut fds = [0 as libc::c_int; 2];
+            let ret = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) };
+            if ret == -1 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(unsafe {
+                Self {
+                    read: OwnedFd::from_raw_fd(fds[0]),
+                    write: OwnedFd::from_raw_fd(fds[1]),
+                }
+            })
+        }
+
+        pub fn read(&self) -> RawFd {
+            self.read.as_raw_fd()
+        }
+
+        pub fn write(&self) -> RawFd {
+            self.write.as_raw_fd()
+        }
+    }
+
+    pub fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+        let ret = unsafe {
+            libc::splice(
+                from,
+                std::ptr::null_mut(),
+                to,
+                std::ptr::null_mut(),
+                len,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+            )
+        };
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(ret as usize)
+    }
+
+    pub fn shutdown_write(socket: &impl AsRawFd) -> io::Result<()> {
+        let ret = unsafe { libc::shutdown(socket.as_raw_fd(), libc::SHUT_WR) };
+        if ret == -1 {
+            // the peer may already be gone
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::ENOTCONN) {
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+use crate::config::{OverloadConfig, OverloadPolicy};
+
+/// Least time between two warnings about a reached limit.
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A cap on simultaneously active connections of one kind.
+///
+/// The cap follows config reloads. When it shrinks below the number of active connections,
+/// the surplus permits are retired as the connections finish.
+#[derive(Clone, Debug)]
+pub struct Limit {
+    name: &'static str,
+    semaphore: Arc<Semaphore>,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    size: usize,
+    /// Permits still to be retired after the cap shrank.
+    debt: usize,
+    /// When a reached limit was last warned about.
+    reported: Option<Instant>,
+    /// Connections that found the limit reached since then.
+    overloaded: u64,
+}
+
+impl Limit {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            semaphore: Arc::new(Semaphore::new(0)),
+            state: Arc::new(Mutex::new(State {
+                size: 0,
+                debt: 0,
+                reported: None,
+                overloaded: 0,
+            })),
+        }
+    }
+
+    /// Takes a permit for a new connection, with at most `size` connections active.
+    ///
+    /// Returns `None` if the cap is reached and `config` says to give up.
+    pub async fn acquire(
+        &self,
+        size: u32,
+        config: &OverloadConfig,
+    ) -> Option<OwnedSemaphorePermit> {
+        self.resize(size as usize);
+
+        let permit = match self.semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                self.report_overload(size);
+                match (config.policy, config.wait_timeout) {
+                    (OverloadPolicy::Reject, _) => None,
+                    (OverloadPolicy::Wait, Some(timeout)) => {
+                        tokio::time::timeout(timeout, self.semaphore.clone().acquire_owned())
+                            .await
+                            .ok()
+                            .and_then(Result::ok)
+                    }
+                    (OverloadPolicy::Wait, None) => {
+                        self.semaphore.clone().acquire_owned().await.ok()
+                    }
+                }
+            }
+        };
+
+        tracing::debug!("{}: {}/{} active", self.name, self.active(), size);
+
+        permit
+    }
+
+    /// Returns the number of connections holding a permit.
+    pub fn active(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        (state.size + state.debt).saturating_sub(self.semaphore.available_permits())
+    }
+
+    /// Warns that the limit is reached, at most once per [`REPORT_INTERVAL`].
+    fn report_overload(&self, size: u32) {
+        let now = Instant::now();
+        let overloaded = {
+            let mut state = self.state.lock().unwrap();
+            state.overloaded += 1;
+            if state
+                .reported
+                .is_some_and(|at| now.duration_since(at) < REPORT_INTERVAL)
+            {
+                return;
+            }
+            state.reported = Some(now);
+            std::mem::take(&mut state.overloaded)
+        };
+
+        tracing::warn!(
+            "{} limit reached: {}/{} active, {} over the limit since the last report",
+            self.name,
+            self.active(),
+            size,
+            overloaded
+        );
+    }
+
+    fn resize(&self, size: usize) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.debt > 0 {
+            state.debt -= self.semaphore.forget_permits(state.debt);
+        }
+
+        if size > state.size {
+            let grow = size - state.size;
+            let repaid = grow.min(state.debt);
+            state.debt -= repaid;
+            self.semaphore.add_permits(grow - repaid);
+        } else if size < state.size {
+            let shrink = state.size - size;
+            state.debt += shrink - self.semaphore.forget_permits(shrink);
+        }
+
+        state.size = size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(policy: OverloadPolicy, wait_timeout: Option<Duration>) -> OverloadConfig {
+        OverloadConfig {
+            policy,
+            wait_timeout,
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_beyond_the_cap() {
+        let limit = Limit::new("test");
+        let reject = config(OverloadPolicy::Reject, None);
+
+        let first = limit.acquire(2, &reject).await;
+        let second = limit.acquire(2, &reject).await;
+        assert!(first.is_some() && second.is_some());
+        assert!(limit.acquire(2, &reject).await.is_none());
+        assert_eq!(limit.active(), 2);
+
+        drop(first);
+        assert!(limit.acquire(2, &reject).await.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_until_a_permit_is_released() {
+        let limit = Limit::new("test");
+        let wait = config(OverloadPolicy::Wait, None);
+
+        let held = limit.acquire(1, &wait).await.unwrap();
+        let waiter = tokio::spawn({
+            let limit = limit.clone();
+            async move { limit.acquire(1, &wait).await.is_some() }
+        });
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert!(!waiter.is_finished());
+
+        drop(held);
+        assert!(waiter.await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_times_out() {
+        let limit = Limit::new("test");
+        let wait = config(OverloadPolicy::Wait, Some(Duration::from_secs(5)));
+
+        let _held = limit.acquire(1, &wait).await.unwrap();
+        let start = Instant::now();
+        assert!(limit.acquire(1, &wait).await.is_none());
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn shrinking_retires_permits_as_connections_finish() {
+        let limit = Limit::new("test");
+        let reject = config(OverloadPolicy::Reject, None);
+
+        let mut held = Vec::new();
+        for _ in 0..3 {
+            held.push(limit.acquire(3, &reject).await.unwrap());
+        }
+
+        // all three are in use, so the cap of one is paid off as they finish
+        assert!(limit.acquire(1, &reject).await.is_none());
+        assert_eq!(limit.active(), 3);
+
+        held.pop();
+        assert!(limit.acquire(1, &reject).await.is_none());
+        assert_eq!(limit.active(), 2);
+
+        held.pop();
+        assert!(limit.acquire(1, &reject).await.is_none());
+        assert_eq!(limit.active(), 1);
+
+        held.pop();
+        assert_eq!(limit.active(), 0);
+        let permit = limit.acquire(1, &reject).await;
+        assert!(permit.is_some());
+        assert!(limit.acquire(1, &reject).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn growing_repays_debt_first() {
+        let limit = Limit::new("test");
+        let reject = config(OverloadPolicy::Reject, None);
+
+        let _first = limit.acquire(2, &reject).await.unwrap();
+        let _second = limit.acquire(2, &reject).await.unwrap();
+
+        // shrinking to zero leaves two permits owed, growing to three repays them
+        limit.resize(0);
+        assert_eq!(limit.state.lock().unwrap().debt, 2);
+        let third = limit.acquire(3, &reject).await;
+        assert!(third.is_some());
+        assert_eq!(limit.state.lock().unwrap().debt, 0);
+        assert!(limit.acquire(3, &reject).await.is_none());
+        assert_eq!(limit.active(), 3);
+    }
+}
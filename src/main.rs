@@ -1,8 +1,13 @@
+mod bandwidth;
 mod config;
 mod connect;
+mod dns_server;
+mod egress;
 mod error;
 mod http;
+mod limit;
 mod proxy;
+mod resolver;
 #[cfg(target_os = "linux")]
 mod route;
 mod serve;
@@ -7,7 +7,10 @@ use tokio::{signal, sync::watch::Sender};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use crate::config::{manager, Config};
+use crate::connect::tcp;
+use crate::dns_server::DnsServer;
 use crate::http::HttpProxy;
+use crate::resolver::Resolvers;
 use crate::serve::serve;
 use crate::Bootstrap;
 
@@ -43,6 +46,7 @@ pub fn run(args: Bootstrap) -> crate::Result<()> {
         debug,
         bind,
         concurrent,
+        inbound,
         ..
     } = config.read().unwrap().clone();
 
@@ -66,6 +70,7 @@ pub fn run(args: Bootstrap) -> crate::Result<()> {
     tracing::info!("Arch: {}", std::env::consts::ARCH);
     tracing::info!("CPUs: {}", cpus);
     tracing::info!("Concurrent: {}", concurrent);
+    config.read().unwrap().warn_ignored();
 
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -81,7 +86,20 @@ pub fn run(args: Bootstrap) -> crate::Result<()> {
             }
         }
 
-        let http_proxy = HttpProxy::new(config);
+        let resolvers = Resolvers::new();
+        tokio::spawn(resolvers.clone().report_cache_stats());
+
+        let dns_server = config.read().unwrap().dns_server.clone();
+        if let Some(dns_server) = dns_server {
+            let server = DnsServer::new(Arc::clone(&config), resolvers.clone());
+            tokio::spawn(async move {
+                if let Err(e) = server.run(dns_server.bind).await {
+                    tracing::error!("DNS server error: {}", e);
+                }
+            });
+        }
+
+        let http_proxy = HttpProxy::new(Arc::clone(&config), resolvers);
 
         let socket = match bind {
             SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
@@ -89,6 +107,11 @@ pub fn run(args: Bootstrap) -> crate::Result<()> {
         };
 
         socket.set_reuseaddr(true)?;
+        if let Some(queue_len) = inbound.fast_open {
+            if let Err(e) = tcp::set_listener_fast_open(&socket, queue_len) {
+                tracing::warn!("failed to enable TCP fast open: {}", e);
+            }
+        }
         socket.bind(bind)?;
 
         let listener = socket.listen(concurrent)?;
@@ -99,7 +122,7 @@ pub fn run(args: Bootstrap) -> crate::Result<()> {
         let tx = Arc::new(tx);
 
         tokio::pin! {
-            let serve_fut = serve(listener, http_proxy)
+            let serve_fut = serve(listener, http_proxy, config)
                 .with_graceful_shutdown(shutdown_signal(Arc::clone(&tx)))
                 .into_future();
 
@@ -0,0 +1,300 @@
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use http::Uri;
+use ipnet::IpNet;
+
+use crate::config::{Config, DnsCacheConfig, DnsConfig, ResolverKind};
+use crate::connect::dns::cache::{CacheConfig, CacheStats};
+use crate::connect::dns::stub::{Nameserver, ResolvConf, StubConfig, Upstream};
+use crate::connect::dns::{
+    BoxResolver, CachingResolver, DohUpstream, DotUpstream, Resolver, SplitResolver,
+    StaticResolver, StubResolver,
+};
+use crate::connect::tcp::TcpConnector;
+use crate::connect::tls::TlsConnector;
+use crate::egress;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// How often the cache counters are logged.
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The resolver stack described by the `[dns]` config section.
+///
+/// The stack is built on first use and rebuilt whenever the section changes, so state such as
+/// open upstream connections survives across requests.
+#[derive(Clone, Debug, Default)]
+pub struct Resolvers {
+    current: Arc<RwLock<Option<Built>>>,
+}
+
+#[derive(Debug)]
+struct Built {
+    /// The config generation the settings were last checked in.
+    generation: u64,
+    config: DnsConfig,
+    cidr: Option<IpNet>,
+    resolver: BoxResolver,
+    cache: Option<CachingResolver<BoxResolver>>,
+}
+
+impl Resolvers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the resolver stack for the `[dns]` section of `config`, where encrypted
+    /// upstreams may be dialed from addresses of its `cidr`.
+    pub fn get(&self, config: &Config) -> BoxResolver {
+        // the section can only have changed when the config was reloaded
+        if let Some(built) = &*self.current.read().unwrap() {
+            if built.generation == config.generation {
+                return built.resolver.clone();
+            }
+        }
+
+        let mut current = self.current.write().unwrap();
+        match &mut *current {
+            Some(built) if built.generation == config.generation => built.resolver.clone(),
+            Some(built) if built.config == config.dns && built.cidr == config.cidr => {
+                built.generation = config.generation;
+                built.resolver.clone()
+            }
+            _ => {
+                tracing::debug!("building {:?} resolver", config.dns.resolver);
+                let (resolver, cache) = build(&config.dns, config.cidr);
+                *current = Some(Built {
+                    generation: config.generation,
+                    config: config.dns.clone(),
+                    cidr: config.cidr,
+                    resolver: resolver.clone(),
+                    cache,
+                });
+                resolver
+            }
+        }
+    }
+
+    /// Returns the counters of the current cache, if one is configured.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        let current = self.current.read().unwrap();
+        current.as_ref()?.cache.as_ref().map(CachingResolver::stats)
+    }
+
+    /// Logs the cache counters every [`REPORT_INTERVAL`], runs forever.
+    pub async fn report_cache_stats(self) {
+        let mut interval = tokio::time::interval_at(
+            tokio::time::Instant::now() + REPORT_INTERVAL,
+            REPORT_INTERVAL,
+        );
+        loop {
+            interval.tick().await;
+            if let Some(stats) = self.cache_stats() {
+                tracing::info!(
+                    "dns cache: {} entries, {} hits, {} stale hits, {} misses, {} prefetches",
+                    stats.entries,
+                    stats.hits,
+                    stats.stale_hits,
+                    stats.misses,
+                    stats.prefetches
+                );
+            }
+        }
+    }
+}
+
+/// Builds the resolver stack, along with its cache if one is configured.
+fn build(
+    config: &DnsConfig,
+    cidr: Option<IpNet>,
+) -> (BoxResolver, Option<CachingResolver<BoxResolver>>) {
+    let resolver = match config.resolver {
+        ResolverKind::System => BoxResolver::new(Resolver::new()),
+        ResolverKind::Stub => {
+            let (nameservers, config) = stub_config(config);
+            let upstreams = nameservers
+                .into_iter()
+                .map(|addr| Arc::new(Nameserver::new(addr)) as Arc<dyn Upstream>)
+                .collect();
+
+            BoxResolver::new(StubResolver::new(upstreams, config))
+        }
+        ResolverKind::Doh => BoxResolver::new(StubResolver::new(
+            doh_upstreams(config, cidr),
+            upstream_config(config),
+        )),
+        ResolverKind::Dot => BoxResolver::new(StubResolver::new(
+            dot_upstreams(config, cidr),
+            upstream_config(config),
+        )),
+    };
+
+    let resolver = if config.hosts.is_empty() && config.rules.is_empty() {
+        resolver
+    } else {
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                let upstreams = rule
+                    .nameservers
+                    .iter()
+                    .map(|addr| Arc::new(Nameserver::new(*addr)) as Arc<dyn Upstream>)
+                    .collect();
+                let resolver = StubResolver::new(upstreams, upstream_config(config));
+
+                (rule.suffix.clone(), BoxResolver::new(resolver))
+            })
+            .collect();
+
+        BoxResolver::new(SplitResolver::new(config.hosts.clone(), rules, resolver))
+    };
+
+    match &config.cache {
+        Some(cache) => {
+            let cache = CachingResolver::new(resolver, cache_config(cache));
+            (BoxResolver::new(cache.clone()), Some(cache))
+        }
+        None => (resolver, None),
+    }
+}
+
+fn cache_config(config: &DnsCacheConfig) -> CacheConfig {
+    CacheConfig {
+        capacity: NonZeroUsize::new(config.capacity).unwrap_or(NonZeroUsize::MIN),
+        min_ttl: config.min_ttl,
+        max_ttl: config.max_ttl,
+        negative_ttl: config.negative_ttl,
+        failure_ttl: config.failure_ttl,
+        stale_ttl: config.stale_ttl,
+        prefetch_hits: config.prefetch_hits,
+    }
+}
+
+fn doh_upstreams(config: &DnsConfig, cidr: Option<IpNet>) -> Vec<Arc<dyn Upstream>> {
+    let upstreams: Vec<Arc<dyn Upstream>> = config
+        .doh
+        .endpoints
+        .iter()
+        .filter_map(|endpoint| match endpoint.url.parse::<Uri>() {
+            Ok(uri) if uri.scheme_str() == Some("https") && uri.host().is_some() => {
+                // each endpoint has its own addresses, so a connection never dials another's
+                let bootstrap = if endpoint.addrs.is_empty() {
+                    BoxResolver::new(Resolver::new())
+                } else {
+                    BoxResolver::new(StaticResolver::new(endpoint.addrs.clone()))
+                };
+                let tls = tls_connector(
+                    config,
+                    bootstrap,
+                    &[b"http/1.1"],
+                    cidr.filter(|_| config.doh.egress),
+                );
+
+                Some(Arc::new(DohUpstream::new(uri, tls)) as Arc<dyn Upstream>)
+            }
+            _ => {
+                tracing::warn!("ignoring invalid doh endpoint: {}", endpoint.url);
+                None
+            }
+        })
+        .collect();
+
+    if upstreams.is_empty() {
+        tracing::warn!("no endpoints configured for the doh resolver");
+    }
+
+    upstreams
+}
+
+fn dot_upstreams(config: &DnsConfig, cidr: Option<IpNet>) -> Vec<Arc<dyn Upstream>> {
+    // servers are configured by address, so nothing is ever looked up
+    let tls = tls_connector(
+        config,
+        BoxResolver::new(StaticResolver::new(Vec::new())),
+        &[b"dot"],
+        cidr.filter(|_| config.dot.egress),
+    );
+
+    if config.dot.servers.is_empty() {
+        tracing::warn!("no servers configured for the dot resolver");
+    }
+
+    config
+        .dot
+        .servers
+        .iter()
+        .map(|server| {
+            Arc::new(DotUpstream::new(
+                server.addr,
+                server.name.clone(),
+                tls.clone(),
+                config.timeout,
+            )) as Arc<dyn Upstream>
+        })
+        .collect()
+}
+
+fn tls_connector(
+    config: &DnsConfig,
+    resolver: BoxResolver,
+    alpn: &[&[u8]],
+    cidr: Option<IpNet>,
+) -> TlsConnector {
+    let mut tcp = TcpConnector::new_with_resolver(resolver);
+    tcp.set_connect_timeout(Some(config.timeout));
+
+    let mut tls = TlsConnector::new(tcp, alpn);
+    if let Some(cidr) = cidr {
+        tls.set_local_address(Some(Arc::new(move || Some(egress::random_addr(cidr)))));
+    }
+
+    tls
+}
+
+/// Settings of resolvers with explicitly configured upstreams, which never read
+/// `/etc/resolv.conf`.
+fn upstream_config(config: &DnsConfig) -> StubConfig {
+    StubConfig {
+        search: config.search.clone(),
+        ndots: config.ndots,
+        timeout: config.timeout,
+        attempts: config.attempts,
+    }
+}
+
+fn stub_config(config: &DnsConfig) -> (Vec<SocketAddr>, StubConfig) {
+    let (nameservers, search) = if config.nameservers.is_empty() {
+        let conf = ResolvConf::read(RESOLV_CONF).unwrap_or_else(|e| {
+            tracing::warn!("failed to read {}: {}", RESOLV_CONF, e);
+            ResolvConf::default()
+        });
+
+        let search = if config.search.is_empty() {
+            conf.search
+        } else {
+            config.search.clone()
+        };
+
+        (conf.nameservers, search)
+    } else {
+        (config.nameservers.clone(), config.search.clone())
+    };
+
+    if nameservers.is_empty() {
+        tracing::warn!("no nameservers configured for the stub resolver");
+    }
+
+    (
+        nameservers,
+        StubConfig {
+            search,
+            ndots: config.ndots,
+            timeout: config.timeout,
+            attempts: config.attempts,
+        },
+    )
+}
@@ -1,28 +1,38 @@
 use std::future::{Future, IntoFuture};
 use std::io;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use futures_util::{pin_mut, FutureExt};
-use hyper_util::rt::{TokioExecutor, TokioIo};
-use hyper_util::server::conn::auto::Builder;
+use hyper::server::conn::http1::Builder;
+use hyper_util::rt::TokioIo;
 use hyper_util::service::TowerToHyperService;
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::watch;
 
+use crate::config::Config;
+use crate::connect::tcp;
 use crate::http::HttpProxy;
+use crate::limit::Limit;
 
-pub fn serve(tcp_listener: TcpListener, http_proxy: HttpProxy) -> Serve {
+pub fn serve(
+    tcp_listener: TcpListener,
+    http_proxy: HttpProxy,
+    config: Arc<RwLock<Config>>,
+) -> Serve {
     Serve {
         tcp_listener,
         http_proxy,
+        config,
     }
 }
 
 pub struct Serve {
     tcp_listener: TcpListener,
     http_proxy: HttpProxy,
+    config: Arc<RwLock<Config>>,
 }
 
 impl Serve {
@@ -33,6 +43,7 @@ impl Serve {
         WithGracefulShutdown {
             tcp_listener: self.tcp_listener,
             http_proxy: self.http_proxy,
+            config: self.config,
             signal,
         }
     }
@@ -51,6 +62,7 @@ impl IntoFuture for Serve {
 pub struct WithGracefulShutdown<F> {
     tcp_listener: TcpListener,
     http_proxy: HttpProxy,
+    config: Arc<RwLock<Config>>,
     signal: F,
 }
 
@@ -77,6 +89,7 @@ where
         let Self {
             tcp_listener,
             http_proxy,
+            config,
             signal,
         } = self;
 
@@ -90,6 +103,8 @@ where
 
         let (close_tx, close_rx) = watch::channel(());
 
+        let limit = Limit::new("client connections");
+
         loop {
             let (io, remote_addr) = tokio::select! {
                 conn = tcp_accept(&tcp_listener) => {
@@ -104,42 +119,76 @@ where
                 }
             };
 
-            let mut version_buffer = [0u8; 1];
-            match io.peek(&mut version_buffer).await {
-                Ok(n) => {
-                    if n == 0 {
-                        tracing::warn!("connection closed before reading version");
-                        continue;
+            let (concurrent, overload, inbound) = {
+                let config = config.read().unwrap();
+                (
+                    config.concurrent,
+                    config.overload.clone(),
+                    config.inbound.clone(),
+                )
+            };
+
+            let limit = limit.clone();
+            let http_proxy = http_proxy.clone();
+            let signal_tx = Arc::clone(&signal_tx);
+            let close_rx = close_rx.clone();
+
+            // waiting for a permit or the first byte must not hold up accepting others
+            tokio::spawn(async move {
+                let permit = tokio::select! {
+                    permit = limit.acquire(concurrent, &overload) => permit,
+                    _ = signal_tx.closed() => {
+                        tracing::trace!("signal received, dropping queued connection {remote_addr:?}");
+                        return;
                     }
+                };
+                let Some(permit) = permit else {
+                    tracing::debug!("rejecting connection {remote_addr:?}, too many connections");
+                    reject(io).await;
+                    return;
+                };
+
+                if let Err(e) =
+                    tcp::set_accepted_options(&io, inbound.keepalive.as_ref(), inbound.user_timeout)
+                {
+                    tracing::warn!("failed to set socket options of {remote_addr:?}: {e:#}");
                 }
-                Err(err) => {
-                    tracing::warn!("failed to read version: {err:#}");
-                    continue;
-                }
-            }
-
-            let io = TokioIo::new(io);
-
-            tracing::trace!("connection {remote_addr:?} accepted");
 
-            match version_buffer[0] {
-                b'G' | b'g' |   // GET
-                b'H' | b'h' |   // HEAD
-                b'P' | b'p' |   // POST
-                b'D' | b'd' |   // DELETE
-                b'C' | b'c' |   // CONNECT
-                b'O' | b'o' |   // OPTIONS
-                b'T' | b't'     // TRACE
-                => {
-                    let hyper_service = TowerToHyperService::new(http_proxy.clone());
-
-                    let signal_tx = Arc::clone(&signal_tx);
-                    let close_rx = close_rx.clone();
+                let mut version_buffer = [0u8; 1];
+                match io.peek(&mut version_buffer).await {
+                    Ok(n) => {
+                        if n == 0 {
+                            tracing::warn!("connection closed before reading version");
+                            return;
+                        }
+                    }
+                    Err(err) => {
+                        tracing::warn!("failed to read version: {err:#}");
+                        return;
+                    }
+                }
 
-                    tokio::spawn(async move {
-                        #[allow(unused_mut)]
-                        let mut builder = Builder::new(TokioExecutor::new());
-                        let conn = builder.serve_connection_with_upgrades(io, hyper_service);
+                let local_addr = io.local_addr().ok();
+                let io = TokioIo::new(io);
+
+                tracing::trace!("connection {remote_addr:?} accepted");
+
+                match version_buffer[0] {
+                    b'G' | b'g' |   // GET
+                    b'H' | b'h' |   // HEAD
+                    b'P' | b'p' |   // POST
+                    b'D' | b'd' |   // DELETE
+                    b'C' | b'c' |   // CONNECT
+                    b'O' | b'o' |   // OPTIONS
+                    b'T' | b't'     // TRACE
+                    => {
+                        let hyper_service = TowerToHyperService::new(http_proxy.with_client(remote_addr, local_addr, permit));
+
+                        // http1 directly rather than the auto builder, which wraps `io` in a
+                        // type CONNECT tunnels cannot unwrap back into the TcpStream
+                        let conn = Builder::new()
+                            .serve_connection(io, hyper_service)
+                            .with_upgrades();
                         pin_mut!(conn);
 
                         let signal_closed = signal_tx.closed().fuse();
@@ -159,12 +208,12 @@ where
                                 }
                             }
                         }
-
-                        drop(close_rx);
-                    });
+                    }
+                    version => tracing::warn!("unsupported version: {:x}", version),
                 }
-                version => tracing::warn!("unsupported version: {:x}", version),
-            }
+
+                drop(close_rx);
+            });
         }
 
         drop(close_rx);
@@ -178,7 +227,18 @@ where
     }
 }
 
-fn is_connection_error(e: &io::Error) -> bool {
+/// Answers a client over the connection limit with 503 and closes the connection.
+async fn reject(mut io: TcpStream) {
+    const RESPONSE: &[u8] =
+        b"HTTP/1.1 503 Service Unavailable\r\nconnection: close\r\ncontent-length: 0\r\n\r\n";
+
+    if let Err(e) = io.write_all(RESPONSE).await {
+        tracing::trace!("failed to reject connection: {e:#}");
+    }
+    let _ = io.shutdown().await;
+}
+
+pub fn is_connection_error(e: &io::Error) -> bool {
     matches!(
         e.kind(),
         io::ErrorKind::ConnectionRefused